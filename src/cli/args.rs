@@ -6,7 +6,7 @@ use std::string::ToString;
 use anyhow::{anyhow, Result};
 use clap::{App, Arg, ArgMatches, SubCommand};
 
-use crate::ShadowPath;
+use crate::{Sha256Implementation, ShadowPath, SymlinkPolicy};
 
 const ENV_GIT_DIR: &str = "GIT_DIR";
 const ENV_SUBSTANCE_DIR: &str = "SUBSTANCE_DIR";
@@ -28,6 +28,7 @@ pub enum Command {
         force: bool,
         remove_after: bool,
         snapshot_dir: PathBuf,
+        take: TakeSnapshotArgs,
     },
     Mount {
         mountpoint: PathBuf,
@@ -48,13 +49,16 @@ pub enum Command {
     CheckBlobs {
         tree: String,
         deep: bool,
+        implementation: Sha256Implementation,
     },
     Sha256Sum {
-        path: PathBuf,
+        paths: Vec<PathBuf>,
+        implementation: Sha256Implementation,
     },
     TakeSnapshot {
         subject: PathBuf,
         out: PathBuf,
+        take: TakeSnapshotArgs,
     },
     PlantSnapshot {
         snapshot: PathBuf,
@@ -62,6 +66,13 @@ pub enum Command {
     StoreSnapshot {
         tree: String,
         subject: PathBuf,
+        snapshot_dir: Option<PathBuf>,
+    },
+    Import {
+        format: ArchiveFormat,
+        archive: PathBuf,
+        relative_path: ShadowPath,
+        force: bool,
     },
     Append {
         big_tree: String,
@@ -79,6 +90,30 @@ pub enum Command {
         tree: String,
         relative_path: ShadowPath,
     },
+    Gc {
+        commits: Vec<String>,
+        delete: bool,
+        yes: bool,
+        grace_days: u64,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    Zip,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TakeSnapshotArgs {
+    pub jobs: usize,
+    pub reference: Option<String>,
+    pub stat_cache: Option<PathBuf>,
+    pub excludes: Vec<String>,
+    pub resume: bool,
+    pub one_file_system: bool,
+    pub skip_mountpoints: bool,
+    pub symlinks: SymlinkPolicy,
 }
 
 fn app<'a, 'b>() -> App<'a, 'b> {
@@ -127,6 +162,7 @@ fn app<'a, 'b>() -> App<'a, 'b> {
                         .default_value("tmp.snapshot")
                         .takes_value(true),
                 )
+                .args(&take_snapshot_args())
                 .arg(Arg::with_name("SUBJECT").required(true).index(1))
                 .arg(Arg::with_name("RELATIVE_PATH").required(true).index(2)),
         )
@@ -134,19 +170,23 @@ fn app<'a, 'b>() -> App<'a, 'b> {
             SubCommand::with_name("mount")
                 .arg(Arg::with_name("MOUNTPOINT").required(true).index(1))
                 .arg(Arg::with_name("TREE").default_value("HEAD").index(2))
-                .arg(Arg::with_name("uid")
-                    .long("--uid")
-                    .short("-u")
-                    .value_name("UID")
-                    .default_value("0")
-                    .takes_value(true)
+                .arg(
+                    Arg::with_name("uid")
+                        .long("--uid")
+                        .short("-u")
+                        .value_name("UID")
+                        .default_value("0")
+                        .takes_value(true)
+                        .help("Owner of nodes recorded without one."),
                 )
-                .arg(Arg::with_name("gid")
-                    .long("--gid")
-                    .short("-g")
-                    .value_name("GID")
-                    .default_value("0")
-                    .takes_value(true)
+                .arg(
+                    Arg::with_name("gid")
+                        .long("--gid")
+                        .short("-g")
+                        .value_name("GID")
+                        .default_value("0")
+                        .takes_value(true)
+                        .help("Owner group of nodes recorded without one."),
                 ),
         )
         .subcommand(
@@ -166,13 +206,23 @@ fn app<'a, 'b>() -> App<'a, 'b> {
         .subcommand(
             SubCommand::with_name("check-blobs")
                 .arg(Arg::with_name("TREE").default_value("HEAD").index(1))
-                .arg(Arg::with_name("deep").long("--deep")),
+                .arg(Arg::with_name("deep").long("--deep"))
+                .arg(sha256_arg()),
         )
         .subcommand(
-            SubCommand::with_name("sha256sum").arg(Arg::with_name("PATH").required(true).index(1)),
+            SubCommand::with_name("sha256sum")
+                .arg(sha256_arg())
+                .arg(
+                    Arg::with_name("PATH")
+                        .required(true)
+                        .multiple(true)
+                        .index(1),
+                )
+                .about("Prints digests in the format of coreutils' sha256sum -b."),
         )
         .subcommand(
             SubCommand::with_name("take-snapshot")
+                .args(&take_snapshot_args())
                 .arg(Arg::with_name("SUBJECT").required(true).index(1))
                 .arg(Arg::with_name("OUT").required(true).index(2)),
         )
@@ -182,9 +232,27 @@ fn app<'a, 'b>() -> App<'a, 'b> {
         )
         .subcommand(
             SubCommand::with_name("store-snapshot")
+                .arg(
+                    Arg::with_name("snapshot_dir")
+                        .long("--snapshot-dir")
+                        .short("-d")
+                        .value_name("SNAPSHOT_DIR")
+                        .takes_value(true)
+                        .help("Warn if SUBJECT is not the subject recorded in SNAPSHOT_DIR."),
+                )
                 .arg(Arg::with_name("TREE").required(true).index(1))
                 .arg(Arg::with_name("SUBJECT").required(true).index(2)),
         )
+        .subcommand(
+            SubCommand::with_name("import-tar")
+                .args(&import_args())
+                .about("Adds the contents of a tar archive, which may be gzipped, to HEAD."),
+        )
+        .subcommand(
+            SubCommand::with_name("import-zip")
+                .args(&import_args())
+                .about("Adds the contents of a zip archive to HEAD."),
+        )
         .subcommand(
             SubCommand::with_name("append")
                 .arg(
@@ -209,6 +277,128 @@ fn app<'a, 'b>() -> App<'a, 'b> {
                 .arg(Arg::with_name("TREE").required(true).index(2))
                 .arg(Arg::with_name("RELATIVE_PATH").required(true).index(3)),
         )
+        .subcommand(
+            SubCommand::with_name("gc")
+                .arg(
+                    Arg::with_name("delete")
+                        .long("delete")
+                        .help("Delete the files rather than list them, once confirmed."),
+                )
+                .arg(
+                    Arg::with_name("yes")
+                        .long("yes")
+                        .short("y")
+                        .requires("delete")
+                        .help("Delete without asking for confirmation."),
+                )
+                .arg(
+                    Arg::with_name("grace")
+                        .long("grace")
+                        .value_name("DAYS")
+                        .default_value("14")
+                        .takes_value(true)
+                        .help("Spare files modified in the last DAYS days."),
+                )
+                .arg(Arg::with_name("COMMIT").multiple(true).index(1))
+                .about(
+                    "Lists the substance files of blobs that no COMMIT uses, by default no commit \
+                     reachable from a ref.",
+                ),
+        )
+}
+
+fn sha256_arg<'a, 'b>() -> Arg<'a, 'b> {
+    Arg::with_name("sha256")
+        .long("sha256")
+        .value_name("IMPLEMENTATION")
+        .possible_values(&["read", "mmap", "coreutils"])
+        .default_value("read")
+        .takes_value(true)
+        .help("Hash in-process by reading or by mapping files, or cross-check with coreutils.")
+}
+
+fn take_snapshot_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("jobs")
+            .long("jobs")
+            .short("j")
+            .value_name("N")
+            .default_value("1")
+            .takes_value(true)
+            .help("Hash up to N files concurrently."),
+        Arg::with_name("reference")
+            .long("reference")
+            .value_name("TREE")
+            .takes_value(true)
+            .requires("stat_cache")
+            .help("Reuse digests from TREE for files unchanged according to the stat cache."),
+        Arg::with_name("stat_cache")
+            .long("stat-cache")
+            .value_name("STAT_CACHE")
+            .takes_value(true)
+            .help("File recording the stat of each file hashed. Updated after success."),
+        Arg::with_name("exclude")
+            .long("exclude")
+            .value_name("PATTERN")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .help(
+                "Exclude paths matching the gitignore-style PATTERN, like a line of .keepignore.",
+            ),
+        Arg::with_name("resume").long("resume").help(
+            "Continue an interrupted snapshot, reusing the digests of files not modified since.",
+        ),
+        Arg::with_name("one_file_system")
+            .long("one-file-system")
+            .help("Do not descend into directories on other filesystems than SUBJECT."),
+        Arg::with_name("skip_mountpoints")
+            .long("skip-mountpoints")
+            .help("Leave mount points out of the snapshot."),
+        Arg::with_name("symlinks")
+            .long("symlinks")
+            .value_name("POLICY")
+            .possible_values(&["preserve", "follow-dirs"])
+            .default_value("preserve")
+            .takes_value(true)
+            .help("Record symlinks as links, or walk symlinks to directories as directories."),
+    ]
+}
+
+fn take_snapshot_match<'a>(submatches: &ArgMatches<'a>) -> Result<TakeSnapshotArgs> {
+    Ok(TakeSnapshotArgs {
+        jobs: submatches.value_of("jobs").unwrap().parse()?,
+        reference: submatches.value_of("reference").map(ToString::to_string),
+        stat_cache: submatches.value_of("stat_cache").map(PathBuf::from),
+        excludes: submatches
+            .values_of("exclude")
+            .map(|values| values.map(ToString::to_string).collect())
+            .unwrap_or_default(),
+        resume: submatches.is_present("resume"),
+        one_file_system: submatches.is_present("one_file_system"),
+        skip_mountpoints: submatches.is_present("skip_mountpoints"),
+        symlinks: submatches.value_of("symlinks").unwrap().parse()?,
+    })
+}
+
+fn import_args<'a, 'b>() -> Vec<Arg<'a, 'b>> {
+    vec![
+        Arg::with_name("force")
+            .long("force")
+            .short("f")
+            .help("Replace RELATIVE_PATH if it exists."),
+        Arg::with_name("ARCHIVE").required(true).index(1),
+        Arg::with_name("RELATIVE_PATH").required(true).index(2),
+    ]
+}
+
+fn import_match<'a>(format: ArchiveFormat, submatches: &ArgMatches<'a>) -> Result<Command> {
+    Ok(Command::Import {
+        format,
+        archive: submatches.value_of("ARCHIVE").unwrap().parse()?,
+        relative_path: submatches.value_of("RELATIVE_PATH").unwrap().parse()?,
+        force: submatches.is_present("force"),
+    })
 }
 
 impl Args {
@@ -262,6 +452,7 @@ impl Args {
                 force: submatches.is_present("force"),
                 remove_after: submatches.is_present("remove_after"),
                 snapshot_dir: submatches.value_of("snapshot_dir").unwrap().parse()?,
+                take: take_snapshot_match(submatches)?,
             }
         } else if let Some(submatches) = matches.subcommand_matches("mount") {
             ensure_git_dir()?;
@@ -301,15 +492,26 @@ impl Args {
             Command::CheckBlobs {
                 tree: submatches.value_of("TREE").unwrap().to_string(),
                 deep: submatches.is_present("deep"),
+                implementation: submatches.value_of("sha256").unwrap().parse()?,
             }
         } else if let Some(submatches) = matches.subcommand_matches("sha256sum") {
             Command::Sha256Sum {
-                path: submatches.value_of("PATH").unwrap().parse()?,
+                paths: submatches
+                    .values_of_os("PATH")
+                    .unwrap()
+                    .map(PathBuf::from)
+                    .collect(),
+                implementation: submatches.value_of("sha256").unwrap().parse()?,
             }
         } else if let Some(submatches) = matches.subcommand_matches("take-snapshot") {
+            let take = take_snapshot_match(submatches)?;
+            if take.reference.is_some() {
+                ensure_git_dir()?;
+            }
             Command::TakeSnapshot {
                 subject: submatches.value_of("SUBJECT").unwrap().parse()?,
                 out: submatches.value_of("OUT").unwrap().parse()?,
+                take,
             }
         } else if let Some(submatches) = matches.subcommand_matches("plant-snapshot") {
             ensure_git_dir()?;
@@ -322,7 +524,16 @@ impl Args {
             Command::StoreSnapshot {
                 tree: submatches.value_of("TREE").unwrap().parse()?,
                 subject: submatches.value_of("SUBJECT").unwrap().parse()?,
+                snapshot_dir: submatches.value_of("snapshot_dir").map(PathBuf::from),
             }
+        } else if let Some(submatches) = matches.subcommand_matches("import-tar") {
+            ensure_git_dir()?;
+            ensure_substance_dir()?;
+            import_match(ArchiveFormat::Tar, submatches)?
+        } else if let Some(submatches) = matches.subcommand_matches("import-zip") {
+            ensure_git_dir()?;
+            ensure_substance_dir()?;
+            import_match(ArchiveFormat::Zip, submatches)?
         } else if let Some(submatches) = matches.subcommand_matches("append") {
             ensure_git_dir()?;
             Command::Append {
@@ -345,6 +556,17 @@ impl Args {
                 tree: submatches.value_of("TREE").unwrap().parse()?,
                 relative_path: submatches.value_of("RELATIVE_PATH").unwrap().parse()?,
             }
+        } else if let Some(submatches) = matches.subcommand_matches("gc") {
+            ensure_git_dir()?;
+            ensure_substance_dir()?;
+            Command::Gc {
+                commits: submatches
+                    .values_of("COMMIT")
+                    .map_or(vec![], |values| values.map(ToString::to_string).collect()),
+                delete: submatches.is_present("delete"),
+                yes: submatches.is_present("yes"),
+                grace_days: submatches.value_of("grace").unwrap().parse()?,
+            }
         } else {
             panic!()
         };
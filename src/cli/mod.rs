@@ -1,14 +1,24 @@
-use std::io::Write;
+use std::collections::BTreeMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::time::{Duration, SystemTime};
 
-use anyhow::Result;
-use git2::{FileMode, Repository};
+use anyhow::{anyhow, bail, Result};
+use git2::{FileMode, Oid, Repository};
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
-use crate::{sha256sum, Database, FilesystemSubstance, ShallowDifferenceSide, Snapshot, Substance};
+use crate::{
+    open_substance, sha256sum_with, ContentSha256, Database, Import, ShadowPath,
+    ShallowDifferenceSide, Snapshot, Substance, TakeSnapshotOptions,
+};
 
 mod args;
+mod progress;
 
-use args::{Args, Command};
+use args::{ArchiveFormat, Args, Command, TakeSnapshotArgs};
+use progress::{human_bytes, ProgressReporter};
 
 pub fn cli_main() -> Result<()> {
     let args = Args::get()?;
@@ -22,9 +32,57 @@ impl Args {
         Ok(Database::new(Repository::open_bare(git_dir)?))
     }
 
-    fn substance(&self) -> Result<FilesystemSubstance> {
+    fn substance(&self) -> Result<Box<dyn Substance>> {
         let substance_dir = self.substance_dir.as_ref().unwrap();
-        Ok(FilesystemSubstance::new(substance_dir))
+        open_substance(substance_dir)
+    }
+
+    fn append_to_head(
+        &self,
+        db: &Database,
+        relative_path: &ShadowPath,
+        mode: FileMode,
+        tree: Oid,
+        force: bool,
+    ) -> Result<()> {
+        let parent = db.repository().head()?.peel_to_commit()?;
+        let big_tree = parent.tree_id();
+        log::info!(
+            "adding snapshot to HEAD^{{tree}} ({}) at {}",
+            big_tree,
+            relative_path
+        );
+        let new_big_tree = db.append(big_tree, relative_path, mode, tree, force)?;
+        let commit = db.commit_simple("x", &db.repository().find_tree(new_big_tree)?, &parent)?;
+        log::info!("new commit is {}. merging --ff-only into HEAD", commit);
+        db.safe_merge(commit)?;
+        Ok(())
+    }
+
+    fn take_snapshot_options(&self, take: &TakeSnapshotArgs) -> Result<TakeSnapshotOptions> {
+        let reference = match &take.reference {
+            Some(reference) => {
+                let db = self.database()?;
+                let tree = db.resolve_treeish(reference)?;
+                let mut shadows = BTreeMap::new();
+                db.shadows(tree, |path, shadow| {
+                    shadows.insert(path.to_path_buf(), shadow.clone());
+                    Ok(())
+                })?;
+                Some(shadows)
+            }
+            None => None,
+        };
+        Ok(TakeSnapshotOptions {
+            jobs: take.jobs,
+            reference,
+            stat_cache: take.stat_cache.clone(),
+            excludes: take.excludes.clone(),
+            resume: take.resume,
+            one_file_system: take.one_file_system,
+            skip_mountpoints: take.skip_mountpoints,
+            symlinks: take.symlinks,
+        })
     }
 
     fn apply_verbosity(&self) {
@@ -47,6 +105,7 @@ impl Args {
                 force,
                 remove_after,
                 snapshot_dir,
+                take,
             } => {
                 let db = self.database()?;
                 let substance = self.substance()?;
@@ -56,31 +115,32 @@ impl Args {
                     subject.display(),
                     snapshot.path().display()
                 );
-                snapshot.take(&subject)?;
+                let mut reporter = ProgressReporter::new("hashing");
+                snapshot.take(&subject, &self.take_snapshot_options(take)?, |progress| {
+                    reporter.report(progress)
+                })?;
                 log::info!("planting snapshot");
                 let (mode, tree) = db.plant_snapshot(&snapshot)?;
                 log::info!("planted: {:06o},{}", u32::from(mode), tree);
                 log::info!("storing snapshot");
-                db.store_snapshot(&substance, tree, &subject)?;
+                let mut reporter = ProgressReporter::new("storing");
+                let changed = db.store_snapshot(&substance, tree, &subject, |progress| {
+                    reporter.report(progress)
+                })?;
+                ensure_stored(&changed)?;
                 // log::info!("adding snapshot to index at {}", relative_path);
                 // db.add_to_index(mode, tree, relative_path)?;
-                let parent = db.repository().head()?.peel_to_commit()?;
-                let big_tree = parent.tree_id();
-                log::info!(
-                    "adding snapshot to HEAD^{{tree}} ({}) at {}",
-                    big_tree,
-                    relative_path
-                );
-                let new_big_tree = db.append(big_tree, &relative_path, mode, tree, *force)?;
-                let commit =
-                    db.commit_simple("x", &db.repository().find_tree(new_big_tree)?, &parent)?;
-                log::info!("new commit is {}. merging --ff-only into HEAD", commit);
-                db.safe_merge(commit)?;
+                self.append_to_head(&db, relative_path, mode, tree, *force)?;
                 if *remove_after {
                     snapshot.remove()?;
                 }
             }
-            Command::Mount { mountpoint, tree, uid, gid } => {
+            Command::Mount {
+                mountpoint,
+                tree,
+                uid,
+                gid,
+            } => {
                 let db = self.database()?;
                 let substance = self.substance()?;
                 let tree = db.resolve_treeish(&tree)?;
@@ -115,30 +175,61 @@ impl Args {
                     Ok(())
                 })?;
             }
-            Command::CheckBlobs { tree, deep } => {
+            Command::CheckBlobs {
+                tree,
+                deep,
+                implementation,
+            } => {
                 let db = self.database()?;
                 let substance = self.substance()?;
                 let tree = db.resolve_treeish(&tree)?;
+                let mut progress = db.unique_shadows_total(tree)?;
+                let mut reporter = ProgressReporter::new("checking");
+                reporter.report(&progress);
                 db.unique_shadows(tree, |path, blob| {
                     // TODO check size
                     if !substance.have_blob(blob.content_hash()) {
                         println!("missing blob: {} {}", blob.content_hash(), path);
                     }
                     if *deep {
-                        if !substance.check_blob(blob.content_hash()).is_ok() {
+                        if !substance
+                            .check_blob(blob.content_hash(), *implementation)
+                            .is_ok()
+                        {
                             println!("invalid blob: {} {}", blob.content_hash(), path);
                         }
                     }
+                    progress.add_file(blob.size().unwrap_or(0));
+                    reporter.report(&progress);
                     Ok(())
                 })?;
             }
-            Command::Sha256Sum { path } => {
-                let blob = sha256sum(path)?;
-                println!("{} *{}", blob, path.display());
+            Command::Sha256Sum {
+                paths,
+                implementation,
+            } => {
+                // Like coreutils, carry on past unreadable files, but fail in the end.
+                let mut failed = 0;
+                let mut stdout = io::stdout();
+                for path in paths {
+                    match sha256sum_with(path, *implementation) {
+                        Ok(digest) => write_sha256sum_line(&mut stdout, &digest, path)?,
+                        Err(err) => {
+                            log::error!("{}: {}", path.display(), err);
+                            failed += 1;
+                        }
+                    }
+                }
+                if failed > 0 {
+                    bail!("{} files could not be read", failed);
+                }
             }
-            Command::TakeSnapshot { subject, out } => {
+            Command::TakeSnapshot { subject, out, take } => {
                 let snapshot = Snapshot::new(out);
-                snapshot.take(&subject)?;
+                let mut reporter = ProgressReporter::new("hashing");
+                snapshot.take(&subject, &self.take_snapshot_options(take)?, |progress| {
+                    reporter.report(progress)
+                })?;
             }
             Command::PlantSnapshot { snapshot } => {
                 let db = self.database()?;
@@ -146,11 +237,50 @@ impl Args {
                 let (mode, tree) = db.plant_snapshot(&snapshot)?;
                 println!("{:06o},{}", u32::from(mode), tree)
             }
-            Command::StoreSnapshot { tree, subject } => {
+            Command::StoreSnapshot {
+                tree,
+                subject,
+                snapshot_dir,
+            } => {
                 let db = self.database()?;
                 let substance = self.substance()?;
-                let tree = db.resolve_treeish(&tree)?;
-                db.store_snapshot(&substance, tree, &subject)?;
+                // A snapshot of a single file is a blob.
+                let tree = db
+                    .resolve_treeish(&tree)
+                    .or_else(|_| db.resolve_blob(&tree))?;
+                if let Some(snapshot_dir) = snapshot_dir {
+                    let recorded = Snapshot::new(snapshot_dir).subject()?;
+                    if fs::canonicalize(subject)? != recorded {
+                        log::warn!(
+                            "storing from {}, but the snapshot was taken of {}",
+                            subject.display(),
+                            recorded.display()
+                        );
+                    }
+                }
+                let mut reporter = ProgressReporter::new("storing");
+                let changed = db.store_snapshot(&substance, tree, &subject, |progress| {
+                    reporter.report(progress)
+                })?;
+                ensure_stored(&changed)?;
+            }
+            Command::Import {
+                format,
+                archive,
+                relative_path,
+                force,
+            } => {
+                let db = self.database()?;
+                let substance = self.substance()?;
+                log::info!("importing {}", archive.display());
+                let file = File::open(archive)?;
+                let import = match format {
+                    ArchiveFormat::Tar => Import::read_tar(file, &substance)?,
+                    ArchiveFormat::Zip => Import::read_zip(file, &substance)?,
+                };
+                let (mode, tree) = db.plant_import(&import)?;
+                log::info!("planted: {:06o},{}", u32::from(mode), tree);
+                self.append_to_head(&db, relative_path, mode, tree, *force)?;
             }
             Command::Append {
                 big_tree,
@@ -161,9 +291,12 @@ impl Args {
             } => {
                 let db = self.database()?;
                 let big_tree = db.resolve_treeish(&big_tree)?;
-                assert_eq!(mode, &format!("{:06o}", u32::from(FileMode::Tree)));
-                let mode = FileMode::Tree;
-                let object = db.resolve_treeish(&object)?;
+                let mode = parse_mode(mode)?;
+                let object = if mode == FileMode::Tree {
+                    db.resolve_treeish(&object)?
+                } else {
+                    db.resolve_blob(&object)?
+                };
                 let new_tree = db.append(big_tree, &relative_path, mode, object, *force)?;
                 println!("{}", new_tree)
             }
@@ -186,7 +319,127 @@ impl Args {
                 assert_eq!(mode, &format!("{:06o}", u32::from(FileMode::Tree)));
                 db.add_to_index(FileMode::Tree, tree, relative_path)?;
             }
+            Command::Gc {
+                commits,
+                delete,
+                yes,
+                grace_days,
+            } => {
+                let db = self.database()?;
+                let substance = self.substance()?;
+                let commits = commits
+                    .iter()
+                    .map(|commit| db.resolve_commit(commit))
+                    .collect::<Result<Vec<_>>>()?;
+                let reachable = db.reachable_blobs(&commits)?;
+                let cutoff = grace_days
+                    .checked_mul(24 * 60 * 60)
+                    .and_then(|secs| SystemTime::now().checked_sub(Duration::from_secs(secs)))
+                    .ok_or_else(|| anyhow!("a grace period of {} days is too long", grace_days))?;
+                let garbage = substance.garbage(&reachable, cutoff)?;
+                let mut size = 0;
+                for path in &garbage {
+                    size += fs::symlink_metadata(path)?.len();
+                }
+                if *delete && !garbage.is_empty() {
+                    // Blobs of snapshots stored but not yet committed are unreachable too.
+                    let what =
+                        format!("{} unreachable files, {}", garbage.len(), human_bytes(size));
+                    if *yes {
+                        log::warn!("deleting {}, which cannot be undone", what);
+                    } else if unsafe { libc::isatty(libc::STDIN_FILENO) } != 1 {
+                        bail!("pass --yes to delete {} when not on a terminal", what);
+                    } else if !confirm(&format!("Delete {}? This cannot be undone.", what))? {
+                        bail!("not deleting {}", what);
+                    }
+                    for path in &garbage {
+                        fs::remove_file(path)?;
+                    }
+                } else {
+                    for path in &garbage {
+                        println!("{}", path.display());
+                    }
+                }
+                log::info!(
+                    "{} {} unreachable files, {}",
+                    if *delete { "deleted" } else { "found" },
+                    garbage.len(),
+                    human_bytes(size)
+                );
+            }
         }
         Ok(())
     }
 }
+
+fn confirm(question: &str) -> Result<bool> {
+    eprint!("{} [y/N] ", question);
+    io::stderr().flush()?;
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
+
+// The format of coreutils' sha256sum -b, which escapes file names with backslashes, newlines or
+// carriage returns, and marks their lines with a leading backslash.
+fn write_sha256sum_line(w: &mut impl Write, digest: &ContentSha256, path: &Path) -> Result<()> {
+    let name = path.as_os_str().as_bytes();
+    if name.iter().any(|b| matches!(b, b'\\' | b'\n' | b'\r')) {
+        let mut escaped = vec![];
+        for b in name {
+            match b {
+                b'\\' => escaped.extend_from_slice(b"\\\\"),
+                b'\n' => escaped.extend_from_slice(b"\\n"),
+                b'\r' => escaped.extend_from_slice(b"\\r"),
+                _ => escaped.push(*b),
+            }
+        }
+        write!(w, "\\{} *", digest)?;
+        w.write_all(&escaped)?;
+    } else {
+        write!(w, "{} *", digest)?;
+        w.write_all(name)?;
+    }
+    w.write_all(b"\n")?;
+    Ok(())
+}
+
+// Trees come from snapshots of directories, and shadows from snapshots of single files.
+fn parse_mode(mode: &str) -> Result<FileMode> {
+    Ok(match u32::from_str_radix(mode, 8)? {
+        0o040000 => FileMode::Tree,
+        0o100644 => FileMode::Blob,
+        0o100755 => FileMode::BlobExecutable,
+        0o120000 => FileMode::Link,
+        _ => bail!("unsupported mode '{}'", mode),
+    })
+}
+
+// The blobs of files that changed after being hashed are missing, so the tree must not be
+// committed.
+fn ensure_stored(changed: &[ShadowPath]) -> Result<()> {
+    if !changed.is_empty() {
+        bail!(
+            "{} files changed since they were hashed and were not stored; snapshot again",
+            changed.len()
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sha256sum_line() {
+        let digest = ContentSha256::from_slice(&[0xab; 32]);
+        let line = |name: &str| {
+            let mut out = vec![];
+            write_sha256sum_line(&mut out, &digest, Path::new(name)).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(line("a b"), format!("{} *a b\n", digest));
+        assert_eq!(line("a\\b\nc\r"), format!("\\{} *a\\\\b\\nc\\r\n", digest));
+    }
+}
@@ -0,0 +1,140 @@
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use crate::Progress;
+
+// Renders progress as a line redrawn in place on a terminal, or otherwise as an occasional log
+// line, for operations that may run overnight.
+pub struct ProgressReporter {
+    what: &'static str,
+    tty: bool,
+    start: Instant,
+    last: Option<Instant>,
+}
+
+impl ProgressReporter {
+    const TTY_INTERVAL: Duration = Duration::from_millis(200);
+    const LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+    pub fn new(what: &'static str) -> Self {
+        Self {
+            what,
+            tty: unsafe { libc::isatty(libc::STDERR_FILENO) } == 1,
+            start: Instant::now(),
+            last: None,
+        }
+    }
+
+    pub fn report(&mut self, progress: &Progress) {
+        let now = Instant::now();
+        let interval = if self.tty {
+            Self::TTY_INTERVAL
+        } else {
+            Self::LOG_INTERVAL
+        };
+        if !progress.is_done() && self.last.map_or(false, |last| now - last < interval) {
+            return;
+        }
+        self.last = Some(now);
+
+        let line = self.line(progress, now - self.start);
+        if self.tty {
+            let mut stderr = io::stderr();
+            let _ = write!(stderr, "\r\x1b[K{}", line);
+            if progress.is_done() {
+                let _ = writeln!(stderr);
+            }
+            let _ = stderr.flush();
+        } else {
+            log::info!("{}", line);
+        }
+    }
+
+    fn line(&self, progress: &Progress, elapsed: Duration) -> String {
+        let mut line = format!(
+            "{}: {}/{} files, {}/{}",
+            self.what,
+            progress.files_done,
+            progress.files_total,
+            human_bytes(progress.bytes_done),
+            human_bytes(progress.bytes_total)
+        );
+        // Totals are taken before the files are read, and files may grow in the meantime.
+        if progress.bytes_total > 0 {
+            line += &format!(
+                " ({:.1}%)",
+                (progress.bytes_done as f64 * 100.0 / progress.bytes_total as f64).min(100.0)
+            );
+        }
+        // Estimated from the average rate so far.
+        let elapsed = elapsed.as_secs_f64();
+        if !progress.is_done() && progress.bytes_done > 0 && elapsed > 0.0 {
+            let rate = progress.bytes_done as f64 / elapsed;
+            let remaining = progress.bytes_total.saturating_sub(progress.bytes_done) as f64 / rate;
+            line += &format!(", {} left", human_duration(remaining as u64));
+        }
+        line
+    }
+}
+
+pub fn human_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB", "PiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+fn human_duration(secs: u64) -> String {
+    if secs >= 3600 {
+        format!("{}h{:02}m", secs / 3600, secs % 3600 / 60)
+    } else if secs >= 60 {
+        format!("{}m{:02}s", secs / 60, secs % 60)
+    } else {
+        format!("{}s", secs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn human() {
+        assert_eq!(human_bytes(1000), "1000 B");
+        assert_eq!(human_bytes(1536), "1.5 KiB");
+        assert_eq!(human_bytes(5 << 40), "5.0 TiB");
+        assert_eq!(human_duration(59), "59s");
+        assert_eq!(human_duration(3 * 3600 + 5 * 60 + 7), "3h05m");
+    }
+
+    #[test]
+    fn line() {
+        let reporter = ProgressReporter::new("hashing");
+        let mut progress = Progress::new(3, 4096);
+        progress.add_file(1024);
+        assert_eq!(
+            reporter.line(&progress, Duration::from_secs(10)),
+            "hashing: 1/3 files, 1.0 KiB/4.0 KiB (25.0%), 30s left"
+        );
+
+        // Files that grew since the totals were taken.
+        progress.add_file(8192);
+        assert_eq!(
+            reporter.line(&progress, Duration::from_secs(10)),
+            "hashing: 2/3 files, 9.0 KiB/4.0 KiB (100.0%), 0s left"
+        );
+        progress.add_file(0);
+        assert_eq!(
+            reporter.line(&progress, Duration::from_secs(10)),
+            "hashing: 3/3 files, 9.0 KiB/4.0 KiB (100.0%)"
+        );
+    }
+}
@@ -1,10 +1,10 @@
 use std::collections::BTreeMap;
-use std::convert::{TryFrom, TryInto};
+use std::convert::TryInto;
 use std::error::Error;
-use std::ffi::OsStr;
-use std::fs::{File, OpenOptions};
+use std::ffi::{OsStr, OsString};
+use std::io::{self, SeekFrom};
 use std::iter::{FromIterator, IntoIterator};
-use std::os::unix::io::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
 use std::path::Path;
 use std::time::{Duration, UNIX_EPOCH};
 
@@ -17,7 +17,11 @@ use git2::{FileMode, ObjectType, Oid, Repository, TreeEntry};
 use libc::{EINVAL, ENOENT};
 use log::error;
 
-use crate::{Database, Shadow, ShadowPathComponent, ShadowTreeEntryName, Substance};
+use super::traverse::read_tree_metadata;
+use crate::{
+    BlobReader, Database, NodeMetadata, Shadow, ShadowPathComponent, ShadowTreeEntryName,
+    SpecialFile, Substance, TreeMetadata,
+};
 
 const FS_NAME: &str = "keep";
 
@@ -68,9 +72,24 @@ macro_rules! fry {
 type Inode = u64;
 
 enum InodeEntry {
-    File { oid: Oid, executable: bool },
-    Link { oid: Oid },
-    Tree { oid: Oid, parent: Inode },
+    // The shadow is read along with the blob, which tells files from special files.
+    File {
+        shadow: Shadow,
+        executable: bool,
+        metadata: Option<NodeMetadata>,
+    },
+    Link {
+        oid: Oid,
+        metadata: Option<NodeMetadata>,
+    },
+    Special {
+        special: SpecialFile,
+        metadata: Option<NodeMetadata>,
+    },
+    Tree {
+        oid: Oid,
+        parent: Inode,
+    },
 }
 
 pub struct DatabaseFilesystem<'a, T> {
@@ -79,18 +98,19 @@ pub struct DatabaseFilesystem<'a, T> {
     family_tree: BTreeMap<(Inode, usize), Inode>,
     next_inode: Inode,
     file_handles: BTreeMap<Inode, SharedFile>,
+    tree_metadata: BTreeMap<Oid, TreeMetadata>,
     substance: T,
     uid: u32,
     gid: u32,
 }
 
 struct SharedFile {
-    file: File,
+    file: Box<dyn BlobReader>,
     reference_count: usize,
 }
 
 impl SharedFile {
-    fn new(file: File) -> Self {
+    fn new(file: Box<dyn BlobReader>) -> Self {
         Self {
             file,
             reference_count: 1,
@@ -121,12 +141,21 @@ impl<'a, T: Substance> DatabaseFilesystem<'a, T> {
             family_tree: BTreeMap::new(),
             next_inode: ROOT_INODE + 1,
             file_handles: BTreeMap::new(),
+            tree_metadata: BTreeMap::new(),
             substance,
             uid,
             gid,
         }
     }
 
+    fn tree_metadata(&mut self, tree: Oid) -> Result<&TreeMetadata> {
+        if !self.tree_metadata.contains_key(&tree) {
+            let metadata = read_tree_metadata(self.repository, tree)?;
+            self.tree_metadata.insert(tree, metadata);
+        }
+        Ok(self.tree_metadata.get(&tree).unwrap())
+    }
+
     fn get_inode(&mut self, parent: Inode, entry: TreeEntry<'static>) -> Result<Inode> {
         let ino = self.next_inode;
         self.next_inode += 1;
@@ -134,17 +163,37 @@ impl<'a, T: Substance> DatabaseFilesystem<'a, T> {
         let mode = entry.filemode();
         let entry = match entry.kind().unwrap() {
             ObjectType::Blob => {
+                let parent_oid = match self.inodes.get(&parent).unwrap() {
+                    InodeEntry::Tree { oid, .. } => *oid,
+                    _ => bail!("parent is not a tree"),
+                };
+                let metadata = self
+                    .tree_metadata(parent_oid)?
+                    .get(&ShadowTreeEntryName::decode(entry.name().unwrap())?)
+                    .cloned();
                 if mode == FileMode::Link.into() {
-                    InodeEntry::Link { oid }
+                    InodeEntry::Link { oid, metadata }
                 } else {
-                    let executable = if mode == FileMode::Blob.into() {
-                        true
-                    } else if mode == FileMode::BlobExecutable.into() {
-                        false
+                    let blob = self.repository.find_blob(oid)?;
+                    if mode == FileMode::Blob.into() && SpecialFile::is_special(blob.content()) {
+                        InodeEntry::Special {
+                            special: SpecialFile::from_bytes(blob.content())?,
+                            metadata,
+                        }
                     } else {
-                        bail!("")
-                    };
-                    InodeEntry::File { oid, executable }
+                        let executable = if mode == FileMode::Blob.into() {
+                            true
+                        } else if mode == FileMode::BlobExecutable.into() {
+                            false
+                        } else {
+                            bail!("")
+                        };
+                        InodeEntry::File {
+                            shadow: Shadow::from_bytes(blob.content())?,
+                            executable,
+                            metadata,
+                        }
+                    }
                 }
             }
             ObjectType::Tree => {
@@ -159,44 +208,73 @@ impl<'a, T: Substance> DatabaseFilesystem<'a, T> {
         Ok(ino)
     }
 
-    fn fetch_attr(&self, ino: u64) -> Result<FileAttr> {
-        let (kind, perm, size) = match self.inodes.get(&ino).unwrap() {
-            InodeEntry::File { oid, executable } => {
+    fn fetch_attr(&mut self, ino: u64) -> Result<FileAttr> {
+        let mut rdev = 0;
+        let (kind, perm, size, metadata) = match self.inodes.get(&ino).unwrap() {
+            InodeEntry::File {
+                shadow,
+                executable,
+                metadata,
+            } => {
                 let kind = FileType::RegularFile;
                 let perm = 0o444 | (if *executable { 0o000 } else { 0o111 });
-                let blob = self.repository.find_blob(oid.clone())?;
-                let shadow = Shadow::from_bytes(blob.content())?;
                 let size = shadow.size().unwrap_or(0);
-                (kind, perm, size)
+                (kind, perm, size, metadata.clone())
             }
-            InodeEntry::Link { oid } => {
+            InodeEntry::Link { oid, metadata } => {
                 let kind = FileType::Symlink;
                 let perm = 0o555;
                 let blob = self.repository.find_blob(oid.clone())?;
                 let size = blob.size().try_into().unwrap();
-                (kind, perm, size)
+                (kind, perm, size, metadata.clone())
             }
-            InodeEntry::Tree { .. } => {
+            InodeEntry::Special { special, metadata } => {
+                let kind = special_file_type(special);
+                let perm = 0o444;
+                if let SpecialFile::CharDevice { major, minor }
+                | SpecialFile::BlockDevice { major, minor } = special
+                {
+                    // The mount is nodev, so device nodes are shown but cannot be opened.
+                    rdev = unsafe { libc::makedev(*major, *minor) } as u32;
+                }
+                (kind, perm, 0, metadata.clone())
+            }
+            InodeEntry::Tree { oid, .. } => {
+                let oid = *oid;
                 let kind = FileType::Directory;
                 let perm = 0o555;
                 let size = 0; // TODO
-                (kind, perm, size)
+                let metadata = self
+                    .tree_metadata(oid)?
+                    .get(&ShadowTreeEntryName::Marker)
+                    .cloned();
+                (kind, perm, size, metadata)
             }
         };
+        // Nodes from before metadata was recorded belong to the owner given for the mount.
+        let (perm, uid, gid, mtime) = match metadata {
+            Some(metadata) => (
+                (metadata.mode & 0o7777) as u16,
+                metadata.uid,
+                metadata.gid,
+                metadata.mtime_as_system_time(),
+            ),
+            None => (perm, self.uid, self.gid, UNIX_EPOCH),
+        };
         Ok(FileAttr {
             ino,
             size,
             blocks: 0,
-            atime: UNIX_EPOCH,
-            mtime: UNIX_EPOCH,
-            ctime: UNIX_EPOCH,
+            atime: mtime,
+            mtime,
+            ctime: mtime,
             crtime: UNIX_EPOCH,
             kind,
             perm,
             nlink: 0,
-            uid: self.uid,
-            gid: self.gid,
-            rdev: 0,
+            uid,
+            gid,
+            rdev,
             blksize: 0,
             flags: 0,
         })
@@ -207,14 +285,11 @@ impl<'a, T: Substance> DatabaseFilesystem<'a, T> {
             shared.increment();
             return Ok(());
         }
-        let oid = match self.inodes.get(&ino).unwrap() {
-            InodeEntry::File { oid, .. } => oid,
+        let shadow = match self.inodes.get(&ino).unwrap() {
+            InodeEntry::File { shadow, .. } => shadow,
             _ => bail!("not a file"),
         };
-        let blob = self.repository.find_blob(oid.clone())?;
-        let shadow = Shadow::from_bytes(blob.content())?;
-        let blob_path = self.substance.blob_path(&shadow.content_hash());
-        let file = OpenOptions::new().read(true).open(blob_path)?;
+        let file = self.substance.open_blob(&shadow.content_hash())?;
         self.file_handles.insert(ino, SharedFile::new(file));
         Ok(())
     }
@@ -240,12 +315,7 @@ impl<'a, T: Substance> Filesystem for DatabaseFilesystem<'a, T> {
             }
         );
         let tree = self.repository.find_tree(oid.clone()).unwrap();
-        let entry_name = name
-            .to_str()
-            .unwrap()
-            .parse::<ShadowPathComponent>()
-            .unwrap()
-            .encode();
+        let entry_name = fry!(reply, ShadowPathComponent::from_bytes(name.as_bytes())).encode();
         for (i, entry) in tree.iter().enumerate() {
             if entry.name().unwrap() == entry_name {
                 let ino = match self.family_tree.get(&(parent, i)) {
@@ -282,7 +352,7 @@ impl<'a, T: Substance> Filesystem for DatabaseFilesystem<'a, T> {
                 ))),
             }
         );
-        let always: Vec<Result<Option<(u64, FileType, String)>>> = vec![
+        let always: Vec<Result<Option<(u64, FileType, OsString)>>> = vec![
             Ok(Some((ino, FileType::Directory, ".".into()))),
             Ok(Some((parent, FileType::Directory, "..".into()))),
         ];
@@ -292,7 +362,7 @@ impl<'a, T: Substance> Filesystem for DatabaseFilesystem<'a, T> {
             .chain(tree.iter().enumerate().map(|(i, entry)| {
                 let name = match ShadowTreeEntryName::decode(entry.name().unwrap()).unwrap() {
                     ShadowTreeEntryName::Marker => return Ok(None),
-                    ShadowTreeEntryName::Child(child) => child.to_string(),
+                    ShadowTreeEntryName::Child(child) => child.as_os_str().to_owned(),
                 };
                 let ino = match self.family_tree.get(&(ino, i)) {
                     Some(ino) => *ino,
@@ -305,6 +375,7 @@ impl<'a, T: Substance> Filesystem for DatabaseFilesystem<'a, T> {
                 let kind = match self.inodes.get(&ino).unwrap() {
                     InodeEntry::File { .. } => FileType::RegularFile,
                     InodeEntry::Link { .. } => FileType::Symlink,
+                    InodeEntry::Special { special, .. } => special_file_type(special),
                     InodeEntry::Tree { .. } => FileType::Directory,
                 };
                 Ok(Some((ino, kind, name)))
@@ -374,16 +445,31 @@ impl<'a, T: Substance> Filesystem for DatabaseFilesystem<'a, T> {
     ) {
         let file = &mut self.file_handles.get_mut(&ino).unwrap().file;
         let mut buf = vec![0u8; size.try_into().unwrap()];
-        let n = unsafe {
-            libc::pread(
-                file.as_raw_fd(),
-                buf.as_mut_ptr() as *mut libc::c_void,
-                size.try_into().unwrap(),
-                offset,
-            )
-        };
-        assert!(n >= 0);
-        let n = usize::try_from(n).unwrap();
+        let n = fry!(reply, read_at(file, &mut buf, offset.try_into().unwrap()));
         reply.data(&buf[..n]);
     }
 }
+
+// Short reads are taken by the kernel to be the end of the file.
+fn read_at(file: &mut dyn BlobReader, buf: &mut [u8], offset: u64) -> Result<usize> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut n = 0;
+    while n < buf.len() {
+        match file.read(&mut buf[n..]) {
+            Ok(0) => break,
+            Ok(m) => n += m,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(n)
+}
+
+fn special_file_type(special: &SpecialFile) -> FileType {
+    match special {
+        SpecialFile::Fifo => FileType::NamedPipe,
+        SpecialFile::Socket => FileType::Socket,
+        SpecialFile::CharDevice { .. } => FileType::CharDevice,
+        SpecialFile::BlockDevice { .. } => FileType::BlockDevice,
+    }
+}
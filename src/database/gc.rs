@@ -0,0 +1,135 @@
+use std::collections::BTreeSet;
+
+use anyhow::Result;
+use git2::{ErrorCode, Oid};
+
+use super::traverse::{OnUnique, TraversalCallbacks, Visit, VisitShadow};
+use crate::{ContentSha256, Database};
+
+impl Database {
+    // The blobs of every commit reachable from `commits`, or from all refs and HEAD if there are
+    // none, for gc. Trees shared between commits are traversed once.
+    pub fn reachable_blobs(&self, commits: &[Oid]) -> Result<BTreeSet<ContentSha256>> {
+        struct ReachableCallbacks {
+            blobs: BTreeSet<ContentSha256>,
+        }
+        impl TraversalCallbacks for ReachableCallbacks {
+            fn on_shadow(&mut self, visit: &Visit<VisitShadow>) -> Result<()> {
+                let shadow = visit.read_shadow()?;
+                self.blobs.insert(shadow.content_hash().clone());
+                Ok(())
+            }
+        }
+
+        let mut revwalk = self.repository().revwalk()?;
+        if commits.is_empty() {
+            // Annotated tags are peeled to their commits, and refs to trees or blobs skipped,
+            // directly or through tags, as a revwalk cannot start from them.
+            for reference in self.repository().references()? {
+                let reference = reference?;
+                match reference.peel_to_commit() {
+                    Ok(commit) => revwalk.push(commit.id())?,
+                    Err(err) if matches!(err.code(), ErrorCode::InvalidSpec | ErrorCode::Peel) => {
+                        log::warn!(
+                            "skipping {}, which is not a commit: {}",
+                            String::from_utf8_lossy(reference.name_bytes()),
+                            err.message()
+                        )
+                    }
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            // An unborn HEAD has no commits.
+            if self.repository().head().is_ok() {
+                revwalk.push_head()?;
+            }
+        }
+        for commit in commits {
+            revwalk.push(*commit)?;
+        }
+
+        let mut callbacks = OnUnique::new(ReachableCallbacks {
+            blobs: BTreeSet::new(),
+        });
+        let mut traverser = self.traverser(&mut callbacks);
+        let mut count = 0;
+        for commit in revwalk {
+            let commit = self.repository().find_commit(commit?)?;
+            traverser.traverse(commit.tree_id())?;
+            count += 1;
+        }
+        let blobs = callbacks.into_inner().blobs;
+        log::info!("{} blobs are reachable from {} commits", blobs.len(), count);
+        Ok(blobs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use git2::{Repository, Signature};
+
+    use super::*;
+    use crate::substance::sha256sum_rust;
+    use crate::{Snapshot, TakeSnapshotOptions};
+
+    #[test]
+    fn refs_to_tags_and_trees() {
+        let dir = std::env::temp_dir().join(format!("keep-gc-refs-{:016x}", rand::random::<u64>()));
+        let subject = dir.join("subject");
+        fs::create_dir_all(&subject).unwrap();
+        fs::write(subject.join("a"), "a").unwrap();
+        let snapshot_path = dir.join("snapshot");
+        let snapshot = Snapshot::new(&snapshot_path);
+        snapshot
+            .take(&subject, &TakeSnapshotOptions::default(), |_| {})
+            .unwrap();
+        let db = Database::new(Repository::init_bare(dir.join("repo")).unwrap());
+        let (_, tree) = db.plant_snapshot(&snapshot).unwrap();
+
+        let repository = db.repository();
+        let signature = Signature::now("x", "x@x").unwrap();
+        let commit = repository
+            .commit(
+                Some("refs/heads/main"),
+                &signature,
+                &signature,
+                "snapshot",
+                &repository.find_tree(tree).unwrap(),
+                &[],
+            )
+            .unwrap();
+        repository
+            .tag(
+                "annotated",
+                &repository.find_object(commit, None).unwrap(),
+                &signature,
+                "tag",
+                false,
+            )
+            .unwrap();
+        repository
+            .reference("refs/trees/tree", tree, false, "tree")
+            .unwrap();
+        let blob = repository.blob(b"tagged").unwrap();
+        for (name, oid) in &[("tree", tree), ("blob", blob)] {
+            repository
+                .tag(
+                    name,
+                    &repository.find_object(*oid, None).unwrap(),
+                    &signature,
+                    "tag",
+                    false,
+                )
+                .unwrap();
+        }
+
+        let blobs = db.reachable_blobs(&[]).unwrap();
+        assert_eq!(
+            blobs.into_iter().collect::<Vec<_>>(),
+            [sha256sum_rust(&subject.join("a")).unwrap()]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -11,9 +11,11 @@ mod traverse;
 mod snapshot;
 mod index;
 mod fs;
+mod gc;
 
 pub use traverse::{
-    TraversalCallbacks, Traverser, Visit, VisitLink, VisitShadow, VisitTree, VisitTreeDecision,
+    TraversalCallbacks, Traverser, Visit, VisitLink, VisitShadow, VisitSpecial, VisitTree,
+    VisitTreeDecision,
 };
 
 pub struct Database {
@@ -38,6 +40,22 @@ impl Database {
             .id())
     }
 
+    pub fn resolve_commit(&self, commitish: &str) -> Result<Oid> {
+        Ok(self
+            .repository()
+            .revparse_single(commitish)?
+            .peel_to_commit()?
+            .id())
+    }
+
+    pub fn resolve_blob(&self, blobish: &str) -> Result<Oid> {
+        Ok(self
+            .repository()
+            .revparse_single(blobish)?
+            .peel_to_blob()?
+            .id())
+    }
+
     pub fn invoke_git(&self, args: &[impl AsRef<str>]) -> Result<()> {
         let mut cmd = Command::new("git");
         cmd.env_clear();
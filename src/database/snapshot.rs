@@ -1,33 +1,79 @@
+use std::collections::BTreeMap;
+use std::fs;
 use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
 use std::path::Path;
 
-use anyhow::Result;
+use anyhow::{bail, ensure, Context, Error, Result};
 use fallible_iterator::{FallibleIterator, Peekable};
-use git2::{FileMode, Oid};
+use git2::{FileMode, ObjectType, Oid};
 
+use crate::snapshot::join_subject;
 use crate::{
-    Database, ShadowTreeEntryName, Snapshot, SnapshotEntries, SnapshotEntry, SnapshotEntryValue,
-    Substance,
+    CopyMethod, Database, DigestMismatch, Import, NodeMetadata, Progress, Shadow, ShadowPath,
+    ShadowTreeEntryName, Snapshot, SnapshotEntry, SnapshotEntryValue, Substance, TreeMetadata,
 };
 
 impl Database {
     pub fn plant_snapshot(&self, snapshot: &Snapshot) -> Result<(FileMode, Oid)> {
-        let mut entries = snapshot.entries()?.peekable();
-        let entry = entries.next()?.unwrap();
-        assert!(entry.path.components().is_empty());
-        let ret = self.plant_snapshot_inner(&mut entries, &entry, self.empty_blob_oid()?)?;
-        assert!(entries.peek()?.is_none());
+        snapshot.verify()?;
+        self.plant_entries(|| snapshot.entries())
+            .with_context(|| format!("planting snapshot '{}'", snapshot.path().display()))
+    }
+
+    pub fn plant_import(&self, import: &Import) -> Result<(FileMode, Oid)> {
+        self.plant_entries(|| Ok(import.entries()))
+    }
+
+    // Entries must be in the order of a snapshot's nodes: the root first, and each tree followed
+    // by its descendants, with siblings sorted. `entries` is called twice, as hard links are
+    // grouped in a first pass.
+    fn plant_entries<T: FallibleIterator<Item = SnapshotEntry, Error = Error>>(
+        &self,
+        entries: impl Fn() -> Result<T>,
+    ) -> Result<(FileMode, Oid)> {
+        // Hard links are grouped by the path of their first link, numbered in order of appearance.
+        let mut link_groups = BTreeMap::new();
+        let mut all_entries = entries()?;
+        while let Some(entry) = all_entries.next()? {
+            if let SnapshotEntryValue::File {
+                link: Some(link), ..
+            } = entry.value
+            {
+                let next_group = link_groups.len() as u64;
+                link_groups.entry(link).or_insert(next_group);
+            }
+        }
+
+        let mut entries = entries()?.peekable();
+        let entry = match entries.next()? {
+            Some(entry) => entry,
+            None => bail!("no entries"),
+        };
+        ensure!(
+            entry.path.components().is_empty(),
+            "first entry '{}' is not the root",
+            entry.path
+        );
+        let ret =
+            self.plant_snapshot_inner(&mut entries, &entry, &link_groups, self.empty_blob_oid()?)?;
+        if let Some(entry) = entries.peek()? {
+            bail!("entry '{}' is out of order", entry.path);
+        }
         Ok(ret)
     }
 
     fn plant_snapshot_inner(
         &self,
-        entries: &mut Peekable<SnapshotEntries<impl io::BufRead>>,
+        entries: &mut Peekable<impl FallibleIterator<Item = SnapshotEntry, Error = Error>>,
         entry: &SnapshotEntry,
+        link_groups: &BTreeMap<ShadowPath, u64>,
         empty_blob_oid: Oid,
     ) -> Result<(FileMode, Oid)> {
         Ok(match &entry.value {
-            SnapshotEntryValue::File { shadow, executable } => {
+            SnapshotEntryValue::File {
+                shadow, executable, ..
+            } => {
                 let mode = if *executable {
                     FileMode::BlobExecutable
                 } else {
@@ -40,50 +86,152 @@ impl Database {
             }
             SnapshotEntryValue::Link { target } => {
                 let mode = FileMode::Link;
-                let content = target.as_bytes();
+                let content = target;
                 let mut writer = self.repository().blob_writer(None)?;
                 writer.write_all(content)?;
                 let oid = writer.commit()?;
                 (mode, oid)
             }
+            SnapshotEntryValue::Special { special } => {
+                let mode = FileMode::Blob;
+                let oid = self.repository().blob(&special.to_bytes())?;
+                (mode, oid)
+            }
             SnapshotEntryValue::Tree => {
                 let mode = FileMode::Tree;
                 let mut builder = self.repository().treebuilder(None)?;
-                builder.insert(
-                    ShadowTreeEntryName::Marker.encode(),
-                    empty_blob_oid,
-                    FileMode::Blob.into(),
-                )?;
+                let mut metadata = TreeMetadata::new();
+                if let Some(tree_metadata) = &entry.metadata {
+                    metadata.insert(ShadowTreeEntryName::Marker, tree_metadata.clone());
+                }
                 while let Some(child_candidate) = entries.peek()? {
-                    if &child_candidate.path.components()
-                        [..child_candidate.path.components().len() - 1]
-                        != entry.path.components()
-                    {
-                        break;
+                    // A second root is left for plant_entries to reject as out of order.
+                    match child_candidate.path.components().split_last() {
+                        Some((_, parent)) if parent == entry.path.components() => {}
+                        _ => break,
                     }
                     let child = entries.next()?.unwrap();
                     let child_name = child.path.components().last().unwrap();
                     let (child_mode, child_oid) =
-                        self.plant_snapshot_inner(entries, &child, empty_blob_oid)?;
+                        self.plant_snapshot_inner(entries, &child, link_groups, empty_blob_oid)?;
                     builder.insert(child_name.encode(), child_oid, child_mode.into())?;
+                    if child_mode != FileMode::Tree {
+                        if let Some(child_metadata) = &child.metadata {
+                            let mut child_metadata = child_metadata.clone();
+                            if let SnapshotEntryValue::File { link, .. } = &child.value {
+                                child_metadata.link_group = link_groups
+                                    .get(link.as_ref().unwrap_or(&child.path))
+                                    .copied();
+                            }
+                            metadata.insert(
+                                ShadowTreeEntryName::Child(child_name.clone()),
+                                child_metadata,
+                            );
+                        }
+                    }
                 }
+                let marker_oid = if metadata.is_empty() {
+                    empty_blob_oid
+                } else {
+                    self.repository().blob(&metadata.to_bytes())?
+                };
+                builder.insert(
+                    ShadowTreeEntryName::Marker.encode(),
+                    marker_oid,
+                    FileMode::Blob.into(),
+                )?;
                 let oid = builder.write()?;
                 (mode, oid)
             }
         })
     }
 
+    // Files that no longer match their shadows, having changed since they were hashed, are
+    // skipped so that the rest can still be stored. Their paths are returned.
     pub fn store_snapshot(
         &self,
         substance: &impl Substance,
-        tree: Oid,
+        object: Oid,
         subject: &Path,
-    ) -> Result<()> {
-        self.unique_shadows(tree, |path, shadow| {
-            let src = subject.join(path.to_string());
-            substance.store(shadow.content_hash(), &src)?;
+        mut on_progress: impl FnMut(&Progress),
+    ) -> Result<Vec<ShadowPath>> {
+        // A snapshot of a single file is a shadow rather than a tree.
+        let root_shadow =
+            if self.repository().find_object(object, None)?.kind() == Some(ObjectType::Blob) {
+                Some(Shadow::from_bytes(
+                    self.repository().find_blob(object)?.content(),
+                )?)
+            } else {
+                None
+            };
+        let mut progress = match &root_shadow {
+            Some(shadow) => Progress::new(1, shadow.size().unwrap_or(0)),
+            None => self.unique_shadows_total(object)?,
+        };
+        on_progress(&progress);
+        // As when it was walked, a subject that is a symlink is followed, which matters for a
+        // snapshot of a single file, whose root would otherwise be the symlink itself.
+        let subject = match fs::canonicalize(subject) {
+            Ok(subject) => subject,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => subject.to_path_buf(),
+            Err(err) => return Err(err.into()),
+        };
+        let mut changed = vec![];
+        let mut methods = BTreeMap::<CopyMethod, u64>::new();
+        let mut store = |path: &ShadowPath, shadow: &Shadow, metadata: Option<&NodeMetadata>| {
+            let src = join_subject(&subject, &path.to_path_buf());
+            let state = file_state(&src)?;
+            // Shadows from before sizes were recorded can only be checked by digest, and those
+            // from before metadata was recorded, or of a single file, have no mtime to check.
+            let modified = match state {
+                Some((size, mtime)) => {
+                    matches!(shadow.size(), Some(s) if s != size)
+                        || matches!(metadata, Some(metadata) if metadata.mtime != mtime)
+                }
+                None => true,
+            };
+            let stored = !modified
+                && match substance.store(shadow.content_hash(), &src) {
+                    Ok(method) => {
+                        if let Some(method) = method {
+                            *methods.entry(method).or_default() += 1;
+                        }
+                        true
+                    }
+                    Err(err) if err.is::<DigestMismatch>() => false,
+                    // Such as when the file was replaced by a directory while it was copied.
+                    Err(_) if file_state(&src)? != state => false,
+                    Err(err) => return Err(err),
+                };
+            if !stored {
+                log::warn!("not storing '{}', which changed since it was hashed", path);
+                changed.push(path.clone());
+            }
+            progress.add_file(shadow.size().unwrap_or(0));
+            on_progress(&progress);
             Ok(())
-        })?;
-        Ok(())
+        };
+        if let Some(shadow) = &root_shadow {
+            store(&ShadowPath::new(), shadow, None)?;
+        } else {
+            self.unique_shadows_with_metadata(object, &mut store)?;
+        }
+        for (method, count) in methods {
+            log::info!("stored {} blobs by {}", count, method);
+        }
+        Ok(changed)
+    }
+}
+
+// The size and mtime of the regular file at `path`, or None if there is none.
+fn file_state(path: &Path) -> Result<Option<(u64, (i64, i64))>> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_file() => Ok(Some((
+            metadata.len(),
+            (metadata.mtime(), metadata.mtime_nsec()),
+        ))),
+        Ok(_) => Ok(None),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err.into()),
     }
 }
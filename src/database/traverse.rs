@@ -1,10 +1,12 @@
 use std::collections::BTreeSet;
-use std::str;
 
 use anyhow::{bail, ensure, Result};
-use git2::{FileMode, ObjectType, Oid, Repository};
+use git2::{Blob, FileMode, ObjectType, Oid, Repository};
 
-use crate::{Database, Shadow, ShadowPath, ShadowTreeEntryName};
+use crate::{
+    Database, NodeMetadata, Progress, Shadow, ShadowPath, ShadowTreeEntryName, SpecialFile,
+    TreeMetadata,
+};
 
 impl Database {
     pub fn traverser<'a, T: TraversalCallbacks>(
@@ -29,6 +31,10 @@ impl Database {
                 let _ = visit.read_link()?;
                 Ok(())
             }
+            fn on_special(&mut self, visit: &Visit<VisitSpecial>) -> Result<()> {
+                let _ = visit.read_special()?;
+                Ok(())
+            }
         }
         let mut callbacks = OnUnique::new(CheckCallbacks);
         self.traverser(&mut callbacks).traverse(tree)
@@ -39,23 +45,83 @@ impl Database {
         tree: Oid,
         callback: impl FnMut(&ShadowPath, &Shadow) -> Result<()>,
     ) -> Result<()> {
-        struct UniqueShadowsCallbacks<T> {
-            callback: T,
-        }
-        impl<T: FnMut(&ShadowPath, &Shadow) -> Result<()>> TraversalCallbacks
-            for UniqueShadowsCallbacks<T>
-        {
-            fn on_shadow(&mut self, visit: &Visit<VisitShadow>) -> Result<()> {
-                let shadow = visit.read_shadow()?;
-                (self.callback)(visit.path, &shadow)?;
-                Ok(())
-            }
-        }
-        let mut callbacks = OnUnique::new(UniqueShadowsCallbacks { callback });
+        let mut callbacks = OnUnique::new(ShadowsCallbacks { callback });
+        self.traverser(&mut callbacks).traverse(tree)
+    }
+
+    // Like unique_shadows, along with the metadata recorded for each shadow in its tree, if any.
+    pub fn unique_shadows_with_metadata(
+        &self,
+        tree: Oid,
+        callback: impl FnMut(&ShadowPath, &Shadow, Option<&NodeMetadata>) -> Result<()>,
+    ) -> Result<()> {
+        let mut callbacks = OnUnique::new(ShadowsWithMetadataCallbacks {
+            callback,
+            parent: None,
+        });
+        self.traverser(&mut callbacks).traverse(tree)
+    }
+
+    // The number and total size of the shadows visited by unique_shadows, as a starting point for
+    // reporting the progress of reading their blobs.
+    pub fn unique_shadows_total(&self, tree: Oid) -> Result<Progress> {
+        let mut total = Progress::default();
+        self.unique_shadows(tree, |_, shadow| {
+            total.files_total += 1;
+            total.bytes_total += shadow.size().unwrap_or(0);
+            Ok(())
+        })?;
+        Ok(total)
+    }
+
+    // Unlike unique_shadows, visits every path, including those sharing a shadow blob.
+    pub fn shadows(
+        &self,
+        tree: Oid,
+        callback: impl FnMut(&ShadowPath, &Shadow) -> Result<()>,
+    ) -> Result<()> {
+        let mut callbacks = ShadowsCallbacks { callback };
         self.traverser(&mut callbacks).traverse(tree)
     }
 }
 
+struct ShadowsCallbacks<T> {
+    callback: T,
+}
+
+impl<T: FnMut(&ShadowPath, &Shadow) -> Result<()>> TraversalCallbacks for ShadowsCallbacks<T> {
+    fn on_shadow(&mut self, visit: &Visit<VisitShadow>) -> Result<()> {
+        let shadow = visit.read_shadow()?;
+        (self.callback)(visit.path, &shadow)?;
+        Ok(())
+    }
+}
+
+struct ShadowsWithMetadataCallbacks<T> {
+    callback: T,
+    // Siblings are visited in turn, so the metadata of their tree is read once.
+    parent: Option<(Oid, TreeMetadata)>,
+}
+
+impl<T: FnMut(&ShadowPath, &Shadow, Option<&NodeMetadata>) -> Result<()>> TraversalCallbacks
+    for ShadowsWithMetadataCallbacks<T>
+{
+    fn on_shadow(&mut self, visit: &Visit<VisitShadow>) -> Result<()> {
+        let shadow = visit.read_shadow()?;
+        let parent = visit.parent();
+        let metadata = match &self.parent {
+            Some((oid, metadata)) if *oid == parent => metadata,
+            _ => {
+                let metadata = read_tree_metadata(visit.repository, parent)?;
+                &self.parent.insert((parent, metadata)).1
+            }
+        };
+        let name = ShadowTreeEntryName::Child(visit.path.components().last().unwrap().clone());
+        (self.callback)(visit.path, &shadow, metadata.get(&name))?;
+        Ok(())
+    }
+}
+
 pub trait TraversalCallbacks {
     fn on_shadow(&mut self, _visit: &Visit<VisitShadow>) -> Result<()> {
         Ok(())
@@ -65,6 +131,10 @@ pub trait TraversalCallbacks {
         Ok(())
     }
 
+    fn on_special(&mut self, _visit: &Visit<VisitSpecial>) -> Result<()> {
+        Ok(())
+    }
+
     fn on_tree(&mut self, _visit: &Visit<VisitTree>) -> Result<VisitTreeDecision> {
         Ok(VisitTreeDecision::Descend)
     }
@@ -82,6 +152,10 @@ impl<T> OnUnique<T> {
             callbacks,
         }
     }
+
+    pub fn into_inner(self) -> T {
+        self.callbacks
+    }
 }
 
 impl<T: TraversalCallbacks> TraversalCallbacks for OnUnique<T> {
@@ -101,6 +175,14 @@ impl<T: TraversalCallbacks> TraversalCallbacks for OnUnique<T> {
         }
     }
 
+    fn on_special(&mut self, visit: &Visit<VisitSpecial>) -> Result<()> {
+        if self.seen.insert(visit.oid()) {
+            self.callbacks.on_special(visit)
+        } else {
+            Ok(())
+        }
+    }
+
     fn on_tree(&mut self, visit: &Visit<VisitTree>) -> Result<VisitTreeDecision> {
         if self.seen.insert(visit.oid()) {
             self.callbacks.on_tree(visit)
@@ -117,11 +199,19 @@ pub struct Visit<'a, T> {
     extra: T,
 }
 
-pub struct VisitShadow {
+// Blobs are loaded once by the traverser, to tell special files from shadows.
+pub struct VisitShadow<'a> {
     executable: bool,
+    parent: Oid,
+    blob: Blob<'a>,
 }
 
 pub struct VisitLink;
+
+pub struct VisitSpecial<'a> {
+    blob: Blob<'a>,
+}
+
 pub struct VisitTree;
 
 pub enum VisitTreeDecision {
@@ -139,24 +229,50 @@ impl<'a, T> Visit<'a, T> {
     }
 }
 
-impl<'a> Visit<'a, VisitShadow> {
+impl<'a> Visit<'a, VisitShadow<'a>> {
     pub fn executable(&self) -> bool {
         self.extra.executable
     }
 
+    // The tree holding the shadow, in whose marker its metadata is recorded.
+    pub fn parent(&self) -> Oid {
+        self.extra.parent
+    }
+
     pub fn read_shadow(&self) -> Result<Shadow> {
-        let blob = self.repository.find_blob(self.oid)?;
-        Ok(Shadow::from_bytes(blob.content())?)
+        Ok(Shadow::from_bytes(self.extra.blob.content())?)
+    }
+}
+
+impl<'a> Visit<'a, VisitSpecial<'a>> {
+    pub fn read_special(&self) -> Result<SpecialFile> {
+        Ok(SpecialFile::from_bytes(self.extra.blob.content())?)
+    }
+}
+
+impl<'a> Visit<'a, VisitTree> {
+    pub fn read_metadata(&self) -> Result<TreeMetadata> {
+        read_tree_metadata(self.repository, self.oid)
     }
 }
 
 impl<'a> Visit<'a, VisitLink> {
-    pub fn read_link(&self) -> Result<String> {
+    pub fn read_link(&self) -> Result<Vec<u8>> {
         let blob = self.repository.find_blob(self.oid)?;
-        Ok(str::from_utf8(blob.content())?.to_owned())
+        Ok(blob.content().to_vec())
     }
 }
 
+pub(crate) fn read_tree_metadata(repository: &Repository, tree: Oid) -> Result<TreeMetadata> {
+    let tree = repository.find_tree(tree)?;
+    let marker = match tree.get_name(&ShadowTreeEntryName::encode_marker()) {
+        Some(marker) => marker,
+        None => bail!("tree is missing marker"),
+    };
+    let blob = repository.find_blob(marker.id())?;
+    Ok(TreeMetadata::from_bytes(blob.content())?)
+}
+
 pub struct Traverser<'a, T> {
     repository: &'a Repository,
     callbacks: &'a mut T,
@@ -164,13 +280,16 @@ pub struct Traverser<'a, T> {
 }
 
 impl<'a, T: TraversalCallbacks> Traverser<'a, T> {
-    fn ensure_blob_is_empty(&mut self, oid: Oid) -> Result<()> {
-        if let Some(expected_oid) = self.empty_blob_oid {
-            ensure!(oid == expected_oid);
-        } else {
-            let blob = self.repository.find_blob(oid)?;
-            ensure!(blob.size() == 0);
+    // Markers are either empty or hold the tree's metadata.
+    fn ensure_marker_is_valid(&mut self, oid: Oid) -> Result<()> {
+        if self.empty_blob_oid == Some(oid) {
+            return Ok(());
+        }
+        let blob = self.repository.find_blob(oid)?;
+        if blob.size() == 0 {
             self.empty_blob_oid = Some(oid);
+        } else {
+            TreeMetadata::from_bytes(blob.content())?;
         }
         Ok(())
     }
@@ -189,6 +308,7 @@ impl<'a, T: TraversalCallbacks> Traverser<'a, T> {
             return Ok(());
         }
 
+        let parent = tree;
         let tree = self.repository.find_tree(tree)?;
 
         let mut first = true;
@@ -202,7 +322,7 @@ impl<'a, T: TraversalCallbacks> Traverser<'a, T> {
                 ensure!(name.is_marker());
                 ensure!(mode == FileMode::Blob.into());
                 ensure!(kind == ObjectType::Blob);
-                self.ensure_blob_is_empty(oid)?;
+                self.ensure_marker_is_valid(oid)?;
                 first = false;
                 continue;
             }
@@ -219,19 +339,34 @@ impl<'a, T: TraversalCallbacks> Traverser<'a, T> {
                             extra: VisitLink,
                         })?;
                     } else {
-                        let executable = if mode == FileMode::Blob.into() {
-                            true
-                        } else if mode == FileMode::BlobExecutable.into() {
-                            false
+                        let blob = self.repository.find_blob(oid)?;
+                        if mode == FileMode::Blob.into() && SpecialFile::is_special(blob.content())
+                        {
+                            self.callbacks.on_special(&Visit {
+                                repository: self.repository,
+                                path: &path,
+                                oid,
+                                extra: VisitSpecial { blob },
+                            })?;
                         } else {
-                            bail!("")
-                        };
-                        self.callbacks.on_shadow(&Visit {
-                            repository: self.repository,
-                            path: &path,
-                            oid,
-                            extra: VisitShadow { executable },
-                        })?;
+                            let executable = if mode == FileMode::Blob.into() {
+                                true
+                            } else if mode == FileMode::BlobExecutable.into() {
+                                false
+                            } else {
+                                bail!("")
+                            };
+                            self.callbacks.on_shadow(&Visit {
+                                repository: self.repository,
+                                path: &path,
+                                oid,
+                                extra: VisitShadow {
+                                    executable,
+                                    parent,
+                                    blob,
+                                },
+                            })?;
+                        }
                     }
                 }
                 ObjectType::Tree => {
@@ -0,0 +1,204 @@
+use std::collections::BTreeMap;
+use std::iter::FromIterator;
+
+use anyhow::{bail, Context, Error, Result};
+use fallible_iterator::FallibleIterator;
+
+use crate::{NodeMetadata, ShadowPath, ShadowPathComponent, SnapshotEntry, SnapshotEntryValue};
+
+mod tar;
+mod zip;
+
+// An archive read into snapshot entries, with the content of its files already stored, so that
+// it can be planted without ever being extracted.
+#[derive(Clone, Debug)]
+pub struct Import {
+    entries: BTreeMap<ShadowPath, SnapshotEntry>,
+}
+
+impl Import {
+    fn new() -> Self {
+        let root = ShadowPath::new();
+        Self {
+            entries: BTreeMap::from_iter([(
+                root.clone(),
+                SnapshotEntry {
+                    path: root,
+                    value: SnapshotEntryValue::Tree,
+                    metadata: None,
+                },
+            )]),
+        }
+    }
+
+    // Ordered by path, which is the order of a snapshot's nodes.
+    pub fn entries(&self) -> impl FallibleIterator<Item = SnapshotEntry, Error = Error> + '_ {
+        fallible_iterator::convert(self.entries.values().cloned().map(Ok))
+    }
+
+    // Archives need not list directories before their contents, or at all. Missing directories
+    // are added without metadata, and a later member replaces an earlier one at the same path.
+    fn insert(
+        &mut self,
+        path: ShadowPath,
+        value: SnapshotEntryValue,
+        metadata: Option<NodeMetadata>,
+    ) -> Result<()> {
+        let mut parent = path.clone();
+        while parent.pop().is_some() {
+            match self.entries.get(&parent) {
+                Some(SnapshotEntry {
+                    value: SnapshotEntryValue::Tree,
+                    ..
+                }) => break,
+                Some(_) => bail!("'{}' is not a directory", parent),
+                None => {
+                    self.entries.insert(
+                        parent.clone(),
+                        SnapshotEntry {
+                            path: parent.clone(),
+                            value: SnapshotEntryValue::Tree,
+                            metadata: None,
+                        },
+                    );
+                }
+            }
+        }
+        if let Some(existing) = self.entries.get(&path) {
+            let is_tree = |value: &SnapshotEntryValue| matches!(value, SnapshotEntryValue::Tree);
+            if is_tree(&existing.value) != is_tree(&value) {
+                bail!("'{}' appears as both a directory and a file", path);
+            }
+        }
+        self.entries.insert(
+            path.clone(),
+            SnapshotEntry {
+                path,
+                value,
+                metadata,
+            },
+        );
+        Ok(())
+    }
+
+    // A hard link to an earlier member shares its shadow.
+    fn insert_hard_link(
+        &mut self,
+        path: ShadowPath,
+        target: ShadowPath,
+        metadata: Option<NodeMetadata>,
+    ) -> Result<()> {
+        let value = match self.entries.get(&target) {
+            Some(SnapshotEntry {
+                value:
+                    SnapshotEntryValue::File {
+                        shadow,
+                        executable,
+                        link,
+                    },
+                ..
+            }) => SnapshotEntryValue::File {
+                shadow: shadow.clone(),
+                executable: *executable,
+                link: Some(link.clone().unwrap_or(target)),
+            },
+            _ => bail!("hard link '{}' to missing file '{}'", path, target),
+        };
+        self.insert(path, value, metadata)
+    }
+}
+
+// Members may be named "./a/b" or "a/b/", and are taken relative to the root. Absolute paths are
+// rejected, as are components such as "..", by `ShadowPathComponent`.
+fn member_path(bytes: &[u8]) -> Result<ShadowPath> {
+    if bytes.starts_with(b"/") {
+        bail!("absolute member path '{}'", String::from_utf8_lossy(bytes));
+    }
+    let mut path = ShadowPath::new();
+    for component in bytes.split(|b| *b == b'/') {
+        if !matches!(component, b"" | b".") {
+            path.push(
+                ShadowPathComponent::from_bytes(component)
+                    .with_context(|| format!("member path '{}'", String::from_utf8_lossy(bytes)))?,
+            );
+        }
+    }
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One line per entry, for the tests of each archive format.
+    pub(super) fn describe(import: &Import) -> Vec<String> {
+        import
+            .entries()
+            .map(|entry| {
+                let value = match entry.value {
+                    SnapshotEntryValue::File {
+                        shadow,
+                        executable,
+                        link,
+                    } => format!(
+                        "file {} {}{}{}",
+                        shadow.size().unwrap(),
+                        &shadow.content_hash().to_hex()[..8],
+                        if executable { " x" } else { "" },
+                        link.map(|link| format!(" = {}", link)).unwrap_or_default()
+                    ),
+                    SnapshotEntryValue::Link { target } => {
+                        format!("link {}", String::from_utf8_lossy(&target))
+                    }
+                    SnapshotEntryValue::Special { special } => format!("{:?}", special),
+                    SnapshotEntryValue::Tree => "tree".to_owned(),
+                };
+                let metadata = entry
+                    .metadata
+                    .map(|metadata| {
+                        format!(
+                            " {:o} {}:{} {}",
+                            metadata.mode, metadata.uid, metadata.gid, metadata.mtime.0
+                        )
+                    })
+                    .unwrap_or_default();
+                Ok(format!("{}: {}{}", entry.path, value, metadata))
+            })
+            .collect()
+            .unwrap()
+    }
+
+    #[test]
+    fn insert() {
+        let mut import = Import::new();
+        let path = |s: &str| member_path(s.as_bytes()).unwrap();
+        import
+            .insert(path("./a/b/c"), SnapshotEntryValue::Tree, None)
+            .unwrap();
+        import
+            .insert(
+                path("a/x"),
+                SnapshotEntryValue::Link { target: vec![] },
+                None,
+            )
+            .unwrap();
+        assert!(import
+            .insert(path("a/x/y"), SnapshotEntryValue::Tree, None)
+            .is_err());
+        assert!(import
+            .insert(
+                path("a/b"),
+                SnapshotEntryValue::Link { target: vec![] },
+                None
+            )
+            .is_err());
+        assert!(member_path(b"a/../b").is_err());
+        assert!(member_path(b"/a").is_err());
+        let paths = import
+            .entries()
+            .map(|entry| Ok(entry.path.to_string()))
+            .collect::<Vec<_>>()
+            .unwrap();
+        assert_eq!(paths, ["", "a", "a/b", "a/b/c", "a/x"]);
+    }
+}
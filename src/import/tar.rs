@@ -0,0 +1,181 @@
+use std::convert::TryFrom;
+use std::io::{self, BufRead, Read};
+
+use anyhow::{Context, Result};
+use flate2::bufread::GzDecoder;
+use tar::{Archive, EntryType};
+
+use super::{member_path, Import};
+use crate::{NodeMetadata, SnapshotEntryValue, SpecialFile, Substance};
+
+impl Import {
+    // Reads a tar archive, compressed with gzip or not, storing the content of each member as it
+    // passes.
+    pub fn read_tar(reader: impl Read, substance: &impl Substance) -> Result<Self> {
+        let mut reader = io::BufReader::new(reader);
+        if reader.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+            Self::read_tar_inner(GzDecoder::new(reader), substance)
+        } else {
+            Self::read_tar_inner(reader, substance)
+        }
+    }
+
+    fn read_tar_inner(reader: impl Read, substance: &impl Substance) -> Result<Self> {
+        let mut import = Self::new();
+        let mut archive = Archive::new(reader);
+        for member in archive.entries()? {
+            let mut member = member?;
+            let header = member.header();
+            let path = member_path(&member.path_bytes())?;
+            let mode = header.mode()?;
+            let metadata = Some(NodeMetadata {
+                mode: mode & 0o7777,
+                uid: u32::try_from(header.uid()?)
+                    .with_context(|| format!("uid of '{}' is out of range", path))?,
+                gid: u32::try_from(header.gid()?)
+                    .with_context(|| format!("gid of '{}' is out of range", path))?,
+                mtime: (
+                    i64::try_from(header.mtime()?)
+                        .with_context(|| format!("mtime of '{}' is out of range", path))?,
+                    0,
+                ),
+                link_group: None,
+            });
+            let device = || -> Result<(u32, u32)> {
+                Ok((
+                    header.device_major()?.unwrap_or(0),
+                    header.device_minor()?.unwrap_or(0),
+                ))
+            };
+            let value = match header.entry_type() {
+                EntryType::Regular | EntryType::Continuous => SnapshotEntryValue::File {
+                    shadow: substance.store_from_reader(&mut member)?,
+                    executable: mode & 0o100 != 0,
+                    link: None,
+                },
+                EntryType::Directory => SnapshotEntryValue::Tree,
+                EntryType::Symlink => SnapshotEntryValue::Link {
+                    target: member.link_name_bytes().unwrap_or_default().into_owned(),
+                },
+                EntryType::Link => {
+                    let target = member_path(&member.link_name_bytes().unwrap_or_default())?;
+                    import.insert_hard_link(path, target, metadata)?;
+                    continue;
+                }
+                EntryType::Fifo => SnapshotEntryValue::Special {
+                    special: SpecialFile::Fifo,
+                },
+                EntryType::Char => {
+                    let (major, minor) = device()?;
+                    SnapshotEntryValue::Special {
+                        special: SpecialFile::CharDevice { major, minor },
+                    }
+                }
+                EntryType::Block => {
+                    let (major, minor) = device()?;
+                    SnapshotEntryValue::Special {
+                        special: SpecialFile::BlockDevice { major, minor },
+                    }
+                }
+                ty => {
+                    log::warn!("skipping {} of type {:?}", path, ty);
+                    continue;
+                }
+            };
+            import.insert(path, value, metadata)?;
+        }
+        Ok(import)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use tar::{Builder, Header};
+
+    use super::super::tests::describe;
+    use super::*;
+    use crate::MockSubstance;
+
+    fn header(entry_type: EntryType, mode: u32, size: u64) -> Header {
+        let mut header = Header::new_gnu();
+        header.set_entry_type(entry_type);
+        header.set_mode(mode);
+        header.set_uid(1000);
+        header.set_gid(100);
+        header.set_mtime(1634774398);
+        header.set_size(size);
+        header
+    }
+
+    fn archive() -> Vec<u8> {
+        let mut builder = Builder::new(vec![]);
+        let mut dir = header(EntryType::Directory, 0o750, 0);
+        builder.append_data(&mut dir, "./d/", io::empty()).unwrap();
+        let mut file = header(EntryType::Regular, 0o755, 5);
+        builder
+            .append_data(&mut file, "d/run", &b"hello"[..])
+            .unwrap();
+        let mut link = header(EntryType::Link, 0o755, 0);
+        link.set_link_name("d/run").unwrap();
+        builder
+            .append_data(&mut link, "e/hard", io::empty())
+            .unwrap();
+        let mut symlink = header(EntryType::Symlink, 0o777, 0);
+        symlink.set_link_name("../d/run").unwrap();
+        builder
+            .append_data(&mut symlink, "e/sym", io::empty())
+            .unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn read() {
+        let substance = MockSubstance::new("/nonexistent");
+        let expected = [
+            ": tree",
+            "d: tree 750 1000:100 1634774398",
+            "d/run: file 5 2cf24dba x 755 1000:100 1634774398",
+            "e: tree",
+            "e/hard: file 5 2cf24dba x = d/run 755 1000:100 1634774398",
+            "e/sym: link ../d/run 777 1000:100 1634774398",
+        ];
+        let import = Import::read_tar(&archive()[..], &substance).unwrap();
+        assert_eq!(describe(&import), expected);
+
+        let mut gzipped = GzEncoder::new(vec![], Compression::default());
+        gzipped.write_all(&archive()).unwrap();
+        let gzipped = gzipped.finish().unwrap();
+        let import = Import::read_tar(&gzipped[..], &substance).unwrap();
+        assert_eq!(describe(&import), expected);
+    }
+
+    #[test]
+    fn out_of_range() {
+        let substance = MockSubstance::new("/nonexistent");
+        let read = |set: &dyn Fn(&mut Header)| {
+            let mut builder = Builder::new(vec![]);
+            let mut file = header(EntryType::Regular, 0o644, 0);
+            set(&mut file);
+            builder.append_data(&mut file, "f", io::empty()).unwrap();
+            let archive = builder.into_inner().unwrap();
+            let err = Import::read_tar(&archive[..], &substance).unwrap_err();
+            err.to_string()
+        };
+        assert_eq!(
+            read(&|header| header.set_uid(1 << 40)),
+            "uid of 'f' is out of range"
+        );
+        assert_eq!(
+            read(&|header| header.set_gid(u64::from(u32::MAX) + 1)),
+            "gid of 'f' is out of range"
+        );
+        assert_eq!(
+            read(&|header| header.set_mtime(u64::MAX)),
+            "mtime of 'f' is out of range"
+        );
+    }
+}
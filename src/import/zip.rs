@@ -0,0 +1,135 @@
+use std::io::{Read, Seek};
+
+use anyhow::Result;
+use zip::{DateTime, ZipArchive};
+
+use super::{member_path, Import};
+use crate::{NodeMetadata, SnapshotEntryValue, Substance};
+
+impl Import {
+    // Reads the members listed in a zip archive's central directory.
+    pub fn read_zip(reader: impl Read + Seek, substance: &impl Substance) -> Result<Self> {
+        let mut import = Self::new();
+        let mut archive = ZipArchive::new(reader)?;
+        for i in 0..archive.len() {
+            let mut member = archive.by_index(i)?;
+            let path = member_path(member.name_raw())?;
+            let is_dir = member.is_dir();
+            // Archives created outside of unix have no mode.
+            let mode = member.unix_mode().unwrap_or(if is_dir {
+                libc::S_IFDIR | 0o755
+            } else {
+                libc::S_IFREG | 0o644
+            });
+            let metadata = Some(NodeMetadata {
+                mode: mode & 0o7777,
+                // Zip archives do not record owners, so imported nodes belong to root, as files
+                // extracted by root would.
+                uid: 0,
+                gid: 0,
+                mtime: (unix_time(&member.last_modified()), 0),
+                link_group: None,
+            });
+            let value = if is_dir {
+                SnapshotEntryValue::Tree
+            } else if mode & libc::S_IFMT == libc::S_IFLNK {
+                let mut target = vec![];
+                member.read_to_end(&mut target)?;
+                SnapshotEntryValue::Link { target }
+            } else {
+                SnapshotEntryValue::File {
+                    shadow: substance.store_from_reader(&mut member)?,
+                    executable: mode & 0o100 != 0,
+                    link: None,
+                }
+            };
+            import.insert(path, value, metadata)?;
+        }
+        Ok(import)
+    }
+}
+
+// Zip timestamps are in the unknown local time of the archiver. They are taken to be UTC.
+fn unix_time(t: &DateTime) -> i64 {
+    // Days from the civil calendar, with years starting in March so that leap days come last.
+    let (month, day) = (i64::from(t.month()), i64::from(t.day()));
+    let year = i64::from(t.year()) - if month <= 2 { 1 } else { 0 };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    let days = era * 146097 + day_of_era - 719468;
+    days * 86400 + i64::from(t.hour()) * 3600 + i64::from(t.minute()) * 60 + i64::from(t.second())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use zip::write::FileOptions;
+    use zip::ZipWriter;
+
+    use super::super::tests::describe;
+    use super::*;
+    use crate::MockSubstance;
+
+    // Replaces the `count` occurrences of `from` with `to`, of the same length, for what
+    // ZipWriter cannot write: names that are not UTF-8, and symlinks.
+    fn patch(archive: &mut [u8], from: &[u8], to: &[u8], count: usize) {
+        let mut found = 0;
+        for i in 0..=archive.len() - from.len() {
+            if &archive[i..i + from.len()] == from {
+                archive[i..i + to.len()].copy_from_slice(to);
+                found += 1;
+            }
+        }
+        assert_eq!(found, count);
+    }
+
+    #[test]
+    fn read() {
+        let options = |mode| {
+            FileOptions::default()
+                .unix_permissions(mode)
+                .last_modified_time(DateTime::from_date_and_time(2021, 10, 20, 23, 59, 58).unwrap())
+        };
+        let mut writer = ZipWriter::new(Cursor::new(vec![]));
+        writer.add_directory("d", options(0o750)).unwrap();
+        writer.start_file("d/run", options(0o755)).unwrap();
+        writer.write_all(b"hello").unwrap();
+        writer.start_file("d/sym", options(0o777)).unwrap();
+        writer.write_all(b"../name").unwrap();
+        writer.start_file("name-X", options(0o644)).unwrap();
+        writer.write_all(b"a").unwrap();
+        let mut archive = writer.finish().unwrap().into_inner();
+        // Names are in both the local headers and the central directory, modes in the latter.
+        patch(&mut archive, b"name-X", b"name-\xff", 2);
+        let mode = |mode: u32| (mode << 16).to_le_bytes();
+        patch(&mut archive, &mode(0o100777), &mode(0o120777), 1);
+
+        let import =
+            Import::read_zip(Cursor::new(archive), &MockSubstance::new("/nonexistent")).unwrap();
+        assert_eq!(
+            describe(&import),
+            [
+                ": tree",
+                "d: tree 750 0:0 1634774398",
+                "d/run: file 5 2cf24dba x 755 0:0 1634774398",
+                "d/sym: link ../name 777 0:0 1634774398",
+                "name-\u{fffd}: file 1 ca978112 644 0:0 1634774398",
+            ]
+        );
+    }
+
+    #[test]
+    fn timestamp() {
+        let t = |year, month, day, hour, minute, second| {
+            unix_time(
+                &DateTime::from_date_and_time(year, month, day, hour, minute, second).unwrap(),
+            )
+        };
+        assert_eq!(t(1980, 1, 1, 0, 0, 0), 315532800);
+        assert_eq!(t(2000, 2, 29, 12, 30, 58), 951827458);
+        assert_eq!(t(2021, 10, 20, 23, 59, 58), 1634774398);
+    }
+}
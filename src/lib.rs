@@ -4,8 +4,12 @@
 
 mod paths;
 mod shadow;
+mod special;
+mod metadata;
+mod progress;
 mod substance;
 mod snapshot;
+mod import;
 mod shallow_diff;
 mod database;
 mod cli;
@@ -18,12 +22,28 @@ pub use crate::{
     shadow::{
         Shadow, ContentSha256,
     },
+    special::{
+        SpecialFile,
+    },
+    metadata::{
+        NodeMetadata, TreeMetadata,
+    },
+    progress::{
+        Progress,
+    },
     substance::{
-        Substance, FilesystemSubstance, MockSubstance,
-        sha256sum,
+        Substance, BlobReader, FilesystemSubstance, MockSubstance, ZstdSubstance, EncryptedSubstance,
+        ChunkedSubstance,
+        SubstanceConfig, Compression, open_substance,
+        CopyMethod, DigestMismatch, Sha256Implementation,
+        sha256sum, sha256sum_with,
     },
     snapshot::{
-        Snapshot, SnapshotEntries, SnapshotEntry, SnapshotEntryValue,
+        Snapshot, SnapshotEntries, SnapshotEntry, SnapshotEntryValue, TakeSnapshotOptions,
+        SnapshotParseError, SnapshotParseErrorKind, SymlinkPolicy,
+    },
+    import::{
+        Import,
     },
     shallow_diff::{
         ShallowDifference, ShallowDifferenceSide,
@@ -32,7 +52,7 @@ pub use crate::{
     database::{
         Database,
         TraversalCallbacks, Traverser,
-        Visit, VisitShadow, VisitLink, VisitTree, VisitTreeDecision,
+        Visit, VisitShadow, VisitLink, VisitSpecial, VisitTree, VisitTreeDecision,
     },
     cli::{
         cli_main,
@@ -0,0 +1,187 @@
+use std::collections::BTreeMap;
+use std::num::ParseIntError;
+use std::str::{self, Utf8Error};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use thiserror::Error;
+
+use crate::paths::ShadowEncodedPathError;
+use crate::ShadowTreeEntryName;
+
+#[derive(Clone, Debug, Hash, PartialOrd, Ord, PartialEq, Eq)]
+pub struct NodeMetadata {
+    pub mode: u32, // permission bits, including setuid, setgid, and sticky
+    pub uid: u32,
+    pub gid: u32,
+    pub mtime: (i64, i64),
+    pub link_group: Option<u64>, // shared by the hard links to a file within a snapshot
+}
+
+impl NodeMetadata {
+    pub fn mtime_as_system_time(&self) -> SystemTime {
+        let (sec, nsec) = self.mtime;
+        let nsec = Duration::from_nanos(nsec as u64);
+        if sec >= 0 {
+            UNIX_EPOCH + Duration::from_secs(sec as u64) + nsec
+        } else {
+            UNIX_EPOCH - Duration::from_secs(sec.unsigned_abs()) + nsec
+        }
+    }
+}
+
+// Stored in the marker blob of each tree. The marker's own name refers to the tree itself, and
+// child names refer to the blobs (shadows and links) in that tree. Child trees carry their own
+// metadata in their own markers. Trees planted before metadata was recorded have an empty marker.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TreeMetadata {
+    entries: BTreeMap<ShadowTreeEntryName, NodeMetadata>,
+}
+
+impl TreeMetadata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn get(&self, name: &ShadowTreeEntryName) -> Option<&NodeMetadata> {
+        self.entries.get(name)
+    }
+
+    pub fn insert(&mut self, name: ShadowTreeEntryName, metadata: NodeMetadata) {
+        self.entries.insert(name, metadata);
+    }
+
+    // Format of each record: "<mode> <uid> <gid> <mtime>.<nsec> <encoded name>\0[ <link group>]\n"
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        for (name, metadata) in &self.entries {
+            bytes.extend_from_slice(
+                format!(
+                    "{:04o} {} {} {}.{:09} {}\0",
+                    metadata.mode,
+                    metadata.uid,
+                    metadata.gid,
+                    metadata.mtime.0,
+                    metadata.mtime.1,
+                    name.encode()
+                )
+                .as_bytes(),
+            );
+            if let Some(link_group) = metadata.link_group {
+                bytes.extend_from_slice(format!(" {}", link_group).as_bytes());
+            }
+            bytes.push(b'\n');
+        }
+        bytes
+    }
+
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self, MetadataError> {
+        let mut metadata = Self::new();
+        while !bytes.is_empty() {
+            let end = bytes
+                .iter()
+                .position(|b| *b == 0)
+                .ok_or(MetadataError::Malformed)?;
+            let newline = end
+                + bytes[end..]
+                    .iter()
+                    .position(|b| *b == b'\n')
+                    .ok_or(MetadataError::Malformed)?;
+            let (name, mut node) = parse_record(str::from_utf8(&bytes[..end])?)?;
+            node.link_group = match &bytes[end + 1..newline] {
+                b"" => None,
+                trailer => match trailer.strip_prefix(b" ") {
+                    Some(link_group) => Some(str::from_utf8(link_group)?.parse()?),
+                    None => return Err(MetadataError::Malformed),
+                },
+            };
+            metadata.insert(name, node);
+            bytes = &bytes[newline + 1..];
+        }
+        Ok(metadata)
+    }
+}
+
+fn parse_record(record: &str) -> Result<(ShadowTreeEntryName, NodeMetadata), MetadataError> {
+    let mut fields = record.splitn(5, ' ');
+    let mut next_field = || fields.next().ok_or(MetadataError::Malformed);
+    let mode = u32::from_str_radix(next_field()?, 8)?;
+    let uid = next_field()?.parse()?;
+    let gid = next_field()?.parse()?;
+    let mtime = match next_field()?.split_once('.') {
+        Some((sec, nsec)) => (sec.parse()?, nsec.parse()?),
+        None => return Err(MetadataError::Malformed),
+    };
+    let name = ShadowTreeEntryName::decode(next_field()?)?;
+    Ok((
+        name,
+        NodeMetadata {
+            mode,
+            uid,
+            gid,
+            mtime,
+            link_group: None,
+        },
+    ))
+}
+
+#[derive(Error, Debug)]
+pub enum MetadataError {
+    #[error("malformed")]
+    Malformed,
+    #[error("error converting from utf-8: {0}")]
+    Utf8Error(
+        #[source]
+        #[from]
+        Utf8Error,
+    ),
+    #[error("malformed number: {0}")]
+    MalformedNumber(
+        #[source]
+        #[from]
+        ParseIntError,
+    ),
+    #[error("malformed name: {0}")]
+    MalformedName(
+        #[source]
+        #[from]
+        ShadowEncodedPathError,
+    ),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tree_metadata() {
+        assert!(TreeMetadata::from_bytes(b"").unwrap().is_empty());
+        assert!(TreeMetadata::from_bytes(b"0644 0 0 0.000000000 0_x\n").is_err());
+        assert!(TreeMetadata::from_bytes(b"0644 0 0 0 0_x\0\n").is_err());
+        assert!(TreeMetadata::from_bytes(b"0644 0 0 0.000000000 x\0\n").is_err());
+        assert!(TreeMetadata::from_bytes(b"0644 0 0 0.000000000 0_x\01\n").is_err());
+        assert!(TreeMetadata::from_bytes(b"0644 0 0 0.000000000 0_x\0 x\n").is_err());
+
+        let bytes = b"0755 1000 100 1634567890.000000001 0\0\n4755 0 0 -1.000000000 0_a b\0 7\n";
+        let metadata = TreeMetadata::from_bytes(bytes).unwrap();
+        assert_eq!(metadata.to_bytes(), bytes);
+        assert_eq!(
+            metadata.get(&ShadowTreeEntryName::Marker).unwrap(),
+            &NodeMetadata {
+                mode: 0o755,
+                uid: 1000,
+                gid: 100,
+                mtime: (1634567890, 1),
+                link_group: None,
+            }
+        );
+        let child = metadata
+            .get(&ShadowTreeEntryName::decode("0_a b").unwrap())
+            .unwrap();
+        assert_eq!(child.mode, 0o4755);
+        assert_eq!(child.link_group, Some(7));
+    }
+}
@@ -1,28 +1,50 @@
+use std::ffi::{OsStr, OsString};
 use std::fmt;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::PathBuf;
 use std::str::{self, FromStr};
 
 use thiserror::Error;
 
+// Components are arbitrary bytes, as in file names. Those which are not valid UTF-8 are
+// percent-encoded in tree entry names (see ShadowTreeEntryName).
 #[derive(Clone, Debug, Hash, PartialOrd, Ord, PartialEq, Eq)]
-pub struct ShadowPathComponent(String); // invariants: matches [^/\0]+ and not in {".", ".."}
+pub struct ShadowPathComponent(Vec<u8>); // invariants: matches [^/\0]+ and not in {".", ".."}
 
 impl ShadowPathComponent {
-    const DISALLOWED_CHARS: &'static [char] = &['/', '\0'];
+    const DISALLOWED_BYTES: &'static [u8] = &[b'/', b'\0'];
 
-    pub fn encode(&self) -> String {
-        ShadowTreeEntryName::encode_child(self)
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ShadowPathError> {
+        match bytes {
+            b"." | b".." => Err(ShadowPathError::DisallowedComponent),
+            _ if bytes.iter().any(|b| Self::DISALLOWED_BYTES.contains(b)) => {
+                Err(ShadowPathError::DisallowedChar)
+            }
+            _ if bytes.is_empty() => Err(ShadowPathError::Empty),
+            _ => Ok(Self(bytes.to_vec())),
+        }
     }
-}
 
-impl AsRef<str> for ShadowPathComponent {
-    fn as_ref(&self) -> &str {
+    pub fn as_bytes(&self) -> &[u8] {
         &self.0
     }
+
+    pub fn as_os_str(&self) -> &OsStr {
+        OsStr::from_bytes(&self.0)
+    }
+
+    pub fn to_str(&self) -> Option<&str> {
+        str::from_utf8(&self.0).ok()
+    }
+
+    pub fn encode(&self) -> String {
+        ShadowTreeEntryName::encode_child(self)
+    }
 }
 
 impl fmt::Display for ShadowPathComponent {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        write!(fmt, "{}", self.0)
+        write!(fmt, "{}", String::from_utf8_lossy(&self.0))
     }
 }
 
@@ -30,12 +52,7 @@ impl FromStr for ShadowPathComponent {
     type Err = ShadowPathError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s {
-            "." | ".." => Err(Self::Err::DisallowedComponent),
-            _ if s.contains(Self::DISALLOWED_CHARS) => Err(Self::Err::DisallowedChar),
-            _ if s.is_empty() => Err(Self::Err::Empty),
-            _ => Ok(Self(s.to_owned())),
-        }
+        Self::from_bytes(s.as_bytes())
     }
 }
 
@@ -59,6 +76,32 @@ impl ShadowPath {
         self.0.pop()
     }
 
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, ShadowPathError> {
+        Ok(Self(if bytes.is_empty() {
+            vec![]
+        } else {
+            bytes
+                .split(|b| *b == b'/')
+                .map(ShadowPathComponent::from_bytes)
+                .collect::<Result<Vec<ShadowPathComponent>, ShadowPathError>>()?
+        }))
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.components()
+            .iter()
+            .map(ShadowPathComponent::as_bytes)
+            .intersperse(&b"/"[..])
+            .flatten()
+            .copied()
+            .collect()
+    }
+
+    // Relative to the root of the tree, with the original bytes of each component.
+    pub fn to_path_buf(&self) -> PathBuf {
+        PathBuf::from(OsString::from_vec(self.to_bytes()))
+    }
+
     pub fn encode(&self) -> String {
         self.components()
             .iter()
@@ -79,10 +122,7 @@ impl ShadowPath {
 
 impl fmt::Display for ShadowPath {
     fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
-        for chunk in self.components().iter().map(AsRef::as_ref).intersperse("/") {
-            write!(fmt, "{}", chunk)?;
-        }
-        Ok(())
+        write!(fmt, "{}", String::from_utf8_lossy(&self.to_bytes()))
     }
 }
 
@@ -90,13 +130,7 @@ impl FromStr for ShadowPath {
     type Err = ShadowPathError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        Ok(Self(if s.is_empty() {
-            vec![]
-        } else {
-            s.split('/')
-                .map(ShadowPathComponent::from_str)
-                .collect::<Result<Vec<ShadowPathComponent>, Self::Err>>()?
-        }))
+        Self::from_bytes(s.as_bytes())
     }
 }
 
@@ -109,6 +143,9 @@ pub enum ShadowTreeEntryName {
 impl ShadowTreeEntryName {
     const MARKER: &'static str = "0";
     const CHILD_PREFIX: &'static str = "0_";
+    // For components which are not valid UTF-8. Bytes which are not part of a valid UTF-8
+    // sequence, along with '%' itself, are escaped as %XX.
+    const ESCAPED_CHILD_PREFIX: &'static str = "0~";
 
     pub fn is_marker(&self) -> bool {
         match self {
@@ -133,7 +170,10 @@ impl ShadowTreeEntryName {
     }
 
     pub fn encode_child(child: &ShadowPathComponent) -> String {
-        format!("{}{}", Self::CHILD_PREFIX, child)
+        match child.to_str() {
+            Some(s) => format!("{}{}", Self::CHILD_PREFIX, s),
+            None => format!("{}{}", Self::ESCAPED_CHILD_PREFIX, escape(child.as_bytes())),
+        }
     }
 
     pub fn decode(s: &str) -> Result<Self, ShadowEncodedPathError> {
@@ -165,14 +205,74 @@ impl FromStr for ShadowTreeEntryName {
         Ok(if s == Self::MARKER {
             Self::Marker
         } else {
-            match s.strip_prefix(Self::CHILD_PREFIX) {
-                Some(child) => Self::Child(child.parse()?),
-                None => return Err(ShadowEncodedPathError::MissingPrefix),
+            if let Some(child) = s.strip_prefix(Self::CHILD_PREFIX) {
+                Self::Child(child.parse()?)
+            } else if let Some(escaped) = s.strip_prefix(Self::ESCAPED_CHILD_PREFIX) {
+                let bytes = unescape(escaped)?;
+                // Only one encoding is allowed per component, so that a tree cannot contain
+                // several entries for the same name.
+                if str::from_utf8(&bytes).is_ok() || escape(&bytes) != escaped {
+                    return Err(ShadowEncodedPathError::NonCanonicalEscape);
+                }
+                Self::Child(ShadowPathComponent::from_bytes(&bytes)?)
+            } else {
+                return Err(ShadowEncodedPathError::MissingPrefix);
             }
         })
     }
 }
 
+fn escape(mut bytes: &[u8]) -> String {
+    let mut escaped = String::new();
+    let escape_byte = |escaped: &mut String, b: u8| escaped.push_str(&format!("%{:02X}", b));
+    while !bytes.is_empty() {
+        let (valid, invalid) = match str::from_utf8(bytes) {
+            Ok(valid) => (valid, &[][..]),
+            Err(err) => {
+                let (valid, rest) = bytes.split_at(err.valid_up_to());
+                let invalid_len = err.error_len().unwrap_or(rest.len());
+                bytes = &rest[invalid_len..];
+                (str::from_utf8(valid).unwrap(), &rest[..invalid_len])
+            }
+        };
+        for c in valid.chars() {
+            if c == '%' {
+                escape_byte(&mut escaped, b'%');
+            } else {
+                escaped.push(c);
+            }
+        }
+        for b in invalid {
+            escape_byte(&mut escaped, *b);
+        }
+        if invalid.is_empty() {
+            break;
+        }
+    }
+    escaped
+}
+
+fn unescape(s: &str) -> Result<Vec<u8>, ShadowEncodedPathError> {
+    let mut bytes = vec![];
+    let mut rest = s.as_bytes();
+    while let Some((b, tail)) = rest.split_first() {
+        if *b == b'%' {
+            let hex = tail
+                .get(..2)
+                .and_then(|hex| str::from_utf8(hex).ok())
+                .ok_or(ShadowEncodedPathError::MalformedEscape)?;
+            let b =
+                u8::from_str_radix(hex, 16).map_err(|_| ShadowEncodedPathError::MalformedEscape)?;
+            bytes.push(b);
+            rest = &tail[2..];
+        } else {
+            bytes.push(*b);
+            rest = tail;
+        }
+    }
+    Ok(bytes)
+}
+
 #[derive(Error, Debug)]
 pub enum ShadowPathError {
     #[error("disallowed component")]
@@ -187,6 +287,10 @@ pub enum ShadowPathError {
 pub enum ShadowEncodedPathError {
     #[error("missing prefix")]
     MissingPrefix,
+    #[error("malformed escape")]
+    MalformedEscape,
+    #[error("non-canonical escape")]
+    NonCanonicalEscape,
     #[error("malformed component")]
     ShadowPathError(
         #[source]
@@ -239,6 +343,36 @@ mod tests {
         );
     }
 
+    #[test]
+    fn bytes() {
+        let component = ShadowPathComponent::from_bytes(b"caf\xe9 100%").unwrap();
+        assert_eq!(component.to_string(), "caf\u{fffd} 100%");
+        assert_eq!(component.encode(), "0~caf%E9 100%25");
+        assert_eq!(
+            ShadowTreeEntryName::decode("0~caf%E9 100%25")
+                .unwrap()
+                .child()
+                .unwrap(),
+            &component
+        );
+        assert_eq!(
+            ShadowPathComponent::from_bytes("100%".as_bytes())
+                .unwrap()
+                .encode(),
+            "0_100%"
+        );
+        assert!(ShadowPathComponent::from_bytes(b"a\xff/b").is_err());
+        assert!(ShadowTreeEntryName::decode("0~abc").is_err());
+        assert!(ShadowTreeEntryName::decode("0~caf%e9").is_err());
+        assert!(ShadowTreeEntryName::decode("0~%E9").is_ok());
+        assert!(ShadowTreeEntryName::decode("0~%E").is_err());
+        assert!(ShadowTreeEntryName::decode("0~%2F%E9").is_err());
+
+        let path = ShadowPath::from_bytes(b"x/\xff\xfe/y").unwrap();
+        assert_eq!(path.encode(), "0_x/0~%FF%FE/0_y");
+        assert_eq!(path.to_bytes(), b"x/\xff\xfe/y");
+    }
+
     #[test]
     fn decode() {
         assert!(ShadowTreeEntryName::decode("xy").is_err());
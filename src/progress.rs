@@ -0,0 +1,28 @@
+// Files and bytes done out of a total known in advance, for operations that read every file of a
+// snapshot or tree, such as hashing, storing and checking blobs.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Progress {
+    pub files_done: u64,
+    pub files_total: u64,
+    pub bytes_done: u64,
+    pub bytes_total: u64,
+}
+
+impl Progress {
+    pub fn new(files_total: u64, bytes_total: u64) -> Self {
+        Self {
+            files_total,
+            bytes_total,
+            ..Self::default()
+        }
+    }
+
+    pub fn add_file(&mut self, size: u64) {
+        self.files_done += 1;
+        self.bytes_done += size;
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.files_done >= self.files_total
+    }
+}
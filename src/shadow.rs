@@ -87,6 +87,10 @@ impl ContentSha256 {
         Self::new(arr)
     }
 
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.digest
+    }
+
     pub fn to_hex(&self) -> String {
         self.to_string()
     }
@@ -0,0 +1,199 @@
+use std::fs;
+use std::path::Path;
+
+use anyhow::{Context, Result};
+use regex::bytes::Regex;
+
+// Gitignore-style exclusion patterns, matched against paths relative to the subject. As with
+// gitignore, the last matching pattern wins, and the contents of an excluded directory are never
+// visited, so they cannot be re-included by a later negated pattern.
+#[derive(Debug, Default)]
+pub struct IgnoreRules {
+    rules: Vec<IgnoreRule>,
+}
+
+#[derive(Debug)]
+struct IgnoreRule {
+    regex: Regex,
+    negated: bool,
+    directory_only: bool,
+}
+
+impl IgnoreRules {
+    pub const FILE_NAME: &'static str = ".keepignore";
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Patterns are of bytes, as paths are, so that they can name files that are not UTF-8.
+    pub fn add_file(&mut self, path: &Path) -> Result<()> {
+        for (i, line) in fs::read(path)?.split(|b| *b == b'\n').enumerate() {
+            self.add(line)
+                .with_context(|| format!("line {} of '{}'", i + 1, path.display()))?;
+        }
+        Ok(())
+    }
+
+    pub fn add(&mut self, pattern: &[u8]) -> Result<()> {
+        let end = pattern.iter().rposition(|b| !b.is_ascii_whitespace());
+        let pattern = &pattern[..end.map_or(0, |end| end + 1)];
+        if pattern.is_empty() || pattern.starts_with(b"#") {
+            return Ok(());
+        }
+        let (negated, pattern) = match pattern.strip_prefix(b"!") {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        let (directory_only, pattern) = match pattern.strip_suffix(b"/") {
+            Some(pattern) => (true, pattern),
+            None => (false, pattern),
+        };
+        self.rules.push(IgnoreRule {
+            regex: Regex::new(&pattern_to_regex(pattern))?,
+            negated,
+            directory_only,
+        });
+        Ok(())
+    }
+
+    pub fn is_excluded(&self, relative_path: &[u8], is_dir: bool) -> bool {
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| (is_dir || !rule.directory_only) && rule.regex.is_match(relative_path))
+            .map_or(false, |rule| !rule.negated)
+    }
+}
+
+// Bytes outside of ASCII are matched one at a time, as by git.
+fn escape(b: u8) -> String {
+    if b.is_ascii() {
+        regex::escape(&char::from(b).to_string())
+    } else {
+        format!("\\x{:02x}", b)
+    }
+}
+
+fn pattern_to_regex(pattern: &[u8]) -> String {
+    // A pattern containing a slash is anchored at the root of the subject. Otherwise, it matches
+    // at any depth.
+    let mut regex = String::from(if pattern.contains(&b'/') {
+        "(?s-u)^"
+    } else {
+        "(?s-u)^(?:.*/)?"
+    });
+    let chars = pattern.strip_prefix(b"/").unwrap_or(pattern);
+    let mut i = 0;
+    while i < chars.len() {
+        match chars[i] {
+            b'*' if chars.get(i + 1) == Some(&b'*') => {
+                let at_start = i == 0 || chars[i - 1] == b'/';
+                if at_start && chars.get(i + 2) == Some(&b'/') {
+                    regex.push_str("(?:.*/)?");
+                    i += 3;
+                } else {
+                    regex.push_str(".*");
+                    i += 2;
+                }
+                continue;
+            }
+            b'*' => regex.push_str("[^/]*"),
+            b'?' => regex.push_str("[^/]"),
+            b'[' => match chars[i + 1..].iter().skip(1).position(|c| *c == b']') {
+                Some(len) => {
+                    let class = &chars[i + 1..i + 2 + len];
+                    regex.push('[');
+                    for (j, c) in class.iter().enumerate() {
+                        match c {
+                            b'!' if j == 0 => regex.push('^'),
+                            b'\\' | b'[' | b'&' | b'~' | b'^' => {
+                                regex.push('\\');
+                                regex.push(char::from(*c));
+                            }
+                            _ if c.is_ascii() => regex.push(char::from(*c)),
+                            _ => regex.push_str(&escape(*c)),
+                        }
+                    }
+                    regex.push(']');
+                    i += len + 3;
+                    continue;
+                }
+                None => regex.push_str(r"\["),
+            },
+            b'\\' if i + 1 < chars.len() => {
+                regex.push_str(&escape(chars[i + 1]));
+                i += 1;
+            }
+            c => regex.push_str(&escape(c)),
+        }
+        i += 1;
+    }
+    regex.push('$');
+    regex
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rules(patterns: &[&str]) -> IgnoreRules {
+        let mut rules = IgnoreRules::new();
+        for pattern in patterns {
+            rules.add(pattern.as_bytes()).unwrap();
+        }
+        rules
+    }
+
+    #[test]
+    fn basename() {
+        let rules = rules(&["*.swp", "node_modules/", "# comment", ""]);
+        assert!(rules.is_excluded(b"a.swp", false));
+        assert!(rules.is_excluded(b"x/y/.a.swp", false));
+        assert!(!rules.is_excluded(b"a.swpx", false));
+        assert!(rules.is_excluded(b"x/node_modules", true));
+        assert!(!rules.is_excluded(b"x/node_modules", false));
+        assert!(!rules.is_excluded(b"# comment", false));
+    }
+
+    #[test]
+    fn anchored() {
+        let rules = rules(&["/.Trash", "cache/*.tmp", "a/**/z"]);
+        assert!(rules.is_excluded(b".Trash", true));
+        assert!(!rules.is_excluded(b"x/.Trash", true));
+        assert!(rules.is_excluded(b"cache/1.tmp", false));
+        assert!(!rules.is_excluded(b"x/cache/1.tmp", false));
+        assert!(!rules.is_excluded(b"cache/x/1.tmp", false));
+        assert!(rules.is_excluded(b"a/z", false));
+        assert!(rules.is_excluded(b"a/b/c/z", false));
+    }
+
+    #[test]
+    fn negation_and_classes() {
+        let rules = rules(&["*.log", "!keep.log", "[!a-c]?.bin", "\\!x"]);
+        assert!(rules.is_excluded(b"x.log", false));
+        assert!(!rules.is_excluded(b"keep.log", false));
+        assert!(rules.is_excluded(b"d1.bin", false));
+        assert!(!rules.is_excluded(b"a1.bin", false));
+        assert!(rules.is_excluded(b"!x", false));
+        assert!(rules.is_excluded(b"caf\xe9.log", false));
+    }
+
+    #[test]
+    fn not_utf8() {
+        let dir = std::env::temp_dir().join(format!("keep-ignore-{:016x}", rand::random::<u64>()));
+        fs::create_dir(&dir).unwrap();
+        let path = dir.join(IgnoreRules::FILE_NAME);
+        fs::write(&path, b"# caf\xe9\ncaf\xe9.txt\r\n[\xe8\xe9]t\xe9/\n").unwrap();
+        let mut rules = IgnoreRules::new();
+        rules.add_file(&path).unwrap();
+        assert!(rules.is_excluded(b"x/caf\xe9.txt", false));
+        assert!(!rules.is_excluded(b"caf\xc3\xa9.txt", false));
+        assert!(rules.is_excluded(b"\xe8t\xe9", true));
+        assert!(!rules.is_excluded(b"\xe8t\xe9", false));
+        fs::write(&path, b"ok\n[z-a]\n").unwrap();
+        let err = IgnoreRules::new().add_file(&path).unwrap_err();
+        assert_eq!(err.to_string(), format!("line 2 of '{}'", path.display()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,651 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::{Path, PathBuf};
+use std::str::{self, FromStr};
+
+use anyhow::{anyhow, bail, Error, Result};
+use fallible_iterator::FallibleIterator;
+use lazy_static::lazy_static;
+use regex::bytes::Regex;
+use thiserror::Error;
+
+use crate::substance::sha256sum_rust;
+use crate::{ContentSha256, NodeMetadata, Shadow, ShadowPath, SpecialFile};
+
+mod take;
+mod stat_cache;
+mod ignore;
+mod mounts;
+
+pub use take::{SymlinkPolicy, TakeSnapshotOptions};
+
+// The subject itself has an empty relative path, which `Path::join` would turn into a trailing
+// slash, and so fail to name a subject that is a file.
+pub(crate) fn join_subject(subject: &Path, relative_path: &Path) -> PathBuf {
+    if relative_path.as_os_str().is_empty() {
+        subject.to_path_buf()
+    } else {
+        subject.join(relative_path)
+    }
+}
+
+pub struct Snapshot<'a> {
+    path: &'a Path,
+}
+
+impl<'a> Snapshot<'a> {
+    const FILES: &'static [&'static str] =
+        &["subject.txt", "sha256sum.txt", "nodes", "files", "digests"];
+
+    pub fn new(path: &'a Path) -> Snapshot {
+        Self { path }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn subject_path(&self) -> PathBuf {
+        self.path().join("subject.txt")
+    }
+
+    fn sha256sum_path(&self) -> PathBuf {
+        self.path().join("sha256sum.txt")
+    }
+
+    fn nodes_path(&self) -> PathBuf {
+        self.path().join("nodes")
+    }
+
+    fn files_path(&self) -> PathBuf {
+        self.path().join("files")
+    }
+
+    fn digests_path(&self) -> PathBuf {
+        self.path().join("digests")
+    }
+
+    // Files that changed while being hashed. Absent from snapshots taken before it was recorded.
+    fn changed_path(&self) -> PathBuf {
+        self.path().join("changed")
+    }
+
+    // How the subject was walked. Absent from snapshots taken before it was recorded.
+    fn options_path(&self) -> PathBuf {
+        self.path().join("options.txt")
+    }
+
+    fn changed(&self) -> Result<BTreeSet<ShadowPath>> {
+        if !self.changed_path().exists() {
+            return Ok(BTreeSet::new());
+        }
+        fs::read(self.changed_path())?
+            .split(|b| *b == 0)
+            .filter(|path| !path.is_empty())
+            .map(|path| {
+                ShadowPath::from_bytes(path).map_err(|_| {
+                    anyhow!(
+                        "malformed path in '{}': {:?}",
+                        self.changed_path().display(),
+                        String::from_utf8_lossy(path)
+                    )
+                })
+            })
+            .collect()
+    }
+
+    pub fn entries(&self) -> Result<SnapshotEntries<impl io::BufRead>> {
+        Ok(SnapshotEntries {
+            nodes_entries: NodesEntries {
+                records: Records::new(
+                    io::BufReader::new(fs::File::open(self.nodes_path())?),
+                    "nodes",
+                ),
+            },
+            digests_entries: DigestsEntries {
+                records: Records::new(
+                    io::BufReader::new(fs::File::open(self.digests_path())?),
+                    "digests",
+                ),
+            },
+            changed: self.changed()?,
+        })
+    }
+
+    // Refuses snapshots with missing files, or whose nodes or digests do not match sha256sum.txt,
+    // as left behind by an interrupted or edited snapshot.
+    pub fn verify(&self) -> Result<()> {
+        for file in Self::FILES {
+            if !self.path().join(file).is_file() {
+                bail!(
+                    "snapshot '{}' is incomplete: missing '{}'",
+                    self.path().display(),
+                    file
+                );
+            }
+        }
+
+        lazy_static! {
+            static ref RE: Regex =
+                Regex::new(r"(?s-u)^(?P<digest>[a-f0-9]{64}) \*(?P<path>[^\n]*)$").unwrap();
+        }
+        let mut expected = BTreeMap::new();
+        for line in fs::read(self.sha256sum_path())?.split(|b| *b == b'\n') {
+            if line.is_empty() {
+                continue;
+            }
+            let malformed = || {
+                anyhow!(
+                    "malformed line in sha256sum.txt: {:?}",
+                    String::from_utf8_lossy(line)
+                )
+            };
+            let caps = RE.captures(line).ok_or_else(malformed)?;
+            // Paths are recorded as given when the snapshot was taken, so only the file name is
+            // meaningful here.
+            let name = Path::new(OsStr::from_bytes(&caps["path"]))
+                .file_name()
+                .ok_or_else(malformed)?
+                .to_owned();
+            let digest = str::from_utf8(&caps["digest"])
+                .unwrap()
+                .parse::<ContentSha256>()?;
+            expected.insert(name, digest);
+        }
+
+        let mut paths = vec![self.nodes_path(), self.digests_path()];
+        for path in &[self.changed_path(), self.options_path()] {
+            if path.exists() {
+                paths.push(path.clone());
+            }
+        }
+        for path in &paths {
+            let name = path.file_name().unwrap();
+            let digest = match expected.get(name) {
+                Some(digest) => digest,
+                None => bail!(
+                    "snapshot '{}' is corrupt: sha256sum.txt has no entry for '{}'",
+                    self.path().display(),
+                    name.to_string_lossy()
+                ),
+            };
+            if &sha256sum_rust(path)? != digest {
+                bail!(
+                    "snapshot '{}' is corrupt: '{}' does not match sha256sum.txt",
+                    self.path().display(),
+                    name.to_string_lossy()
+                );
+            }
+        }
+        Ok(())
+    }
+
+    pub fn subject(&self) -> Result<PathBuf> {
+        let mut subject = fs::read(self.subject_path())?;
+        if subject.pop() != Some(b'\n') {
+            bail!("malformed subject.txt in '{}'", self.path().display());
+        }
+        Ok(PathBuf::from(OsString::from_vec(subject)))
+    }
+
+    pub fn remove(&self) -> Result<()> {
+        for file in Self::FILES {
+            fs::remove_file(&self.path().join(file))?;
+        }
+        for path in &[self.changed_path(), self.options_path()] {
+            if path.exists() {
+                fs::remove_file(path)?;
+            }
+        }
+        fs::remove_dir(self.path())?;
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct SnapshotEntry {
+    pub path: ShadowPath,
+    pub value: SnapshotEntryValue,
+    pub metadata: Option<NodeMetadata>, // None for snapshots taken before metadata was recorded
+}
+
+#[derive(Clone, Debug)]
+pub enum SnapshotEntryValue {
+    // `link` is the path of an earlier file in the snapshot that this one is a hard link to.
+    File {
+        shadow: Shadow,
+        executable: bool,
+        link: Option<ShadowPath>,
+    },
+    Link {
+        target: Vec<u8>,
+    },
+    Special {
+        special: SpecialFile,
+    },
+    Tree,
+}
+
+pub struct SnapshotEntries<T> {
+    nodes_entries: NodesEntries<T>,
+    digests_entries: DigestsEntries<T>,
+    changed: BTreeSet<ShadowPath>,
+}
+
+impl<T: io::BufRead> FallibleIterator for SnapshotEntries<T> {
+    type Item = SnapshotEntry;
+    type Error = Error;
+
+    // Files that changed while the snapshot was taken are left out, as their digests cannot be
+    // trusted.
+    fn next(&mut self) -> Result<Option<Self::Item>, Self::Error> {
+        while let Some(entry) = self.read_entry()? {
+            if !self.changed.contains(&entry.path) {
+                return Ok(Some(entry));
+            }
+            log::warn!(
+                "leaving out '{}', which changed while being hashed",
+                entry.path
+            );
+        }
+        Ok(None)
+    }
+}
+
+impl<T: io::BufRead> SnapshotEntries<T> {
+    fn read_entry(&mut self) -> Result<Option<SnapshotEntry>> {
+        let node_line = match self.nodes_entries.next()? {
+            Some(node_line) => node_line,
+            None => {
+                if self.digests_entries.next()?.is_some() {
+                    return Err(self
+                        .digests_entries
+                        .records
+                        .error(SnapshotParseErrorKind::ExtraDigest)
+                        .into());
+                }
+                return Ok(None);
+            }
+        };
+        let nodes_records = &self.nodes_entries.records;
+        let path = ShadowPath::from_bytes(&node_line.path)
+            .map_err(|_| nodes_records.error(SnapshotParseErrorKind::MalformedField("path")))?;
+        if path.components().is_empty() && nodes_records.record != 1 {
+            return Err(nodes_records
+                .error(SnapshotParseErrorKind::MisplacedRoot)
+                .into());
+        }
+        let metadata = node_line.metadata();
+        let value = match node_line.ty {
+            'd' => SnapshotEntryValue::Tree,
+            'l' => SnapshotEntryValue::Link {
+                target: node_line.target,
+            },
+            'f' => {
+                let link = match node_line.target.as_slice() {
+                    b"" => None,
+                    target => Some(ShadowPath::from_bytes(target).map_err(|_| {
+                        nodes_records.error(SnapshotParseErrorKind::MalformedField("target"))
+                    })?),
+                };
+                let digest_line = match self.digests_entries.next()? {
+                    Some(digest_line) => digest_line,
+                    None => {
+                        return Err(self
+                            .digests_entries
+                            .records
+                            .error(SnapshotParseErrorKind::MissingDigest(path.to_string()))
+                            .into())
+                    }
+                };
+                let digests_records = &self.digests_entries.records;
+                if digest_line.path != node_line.path {
+                    return Err(digests_records
+                        .error(SnapshotParseErrorKind::DigestPathMismatch {
+                            expected: path.to_string(),
+                            found: String::from_utf8_lossy(&digest_line.path).into_owned(),
+                        })
+                        .into());
+                }
+                SnapshotEntryValue::File {
+                    shadow: Shadow::new(
+                        digests_records.parse_field("digest", &digest_line.digest)?,
+                        node_line.size,
+                    ),
+                    executable: node_line.is_executable(),
+                    link,
+                }
+            }
+            'p' => SnapshotEntryValue::Special {
+                special: SpecialFile::Fifo,
+            },
+            's' => SnapshotEntryValue::Special {
+                special: SpecialFile::Socket,
+            },
+            'c' | 'b' => {
+                let (major, minor) = node_line.rdev.ok_or_else(|| {
+                    nodes_records.error(SnapshotParseErrorKind::MissingDeviceNumber)
+                })?;
+                SnapshotEntryValue::Special {
+                    special: if node_line.ty == 'c' {
+                        SpecialFile::CharDevice { major, minor }
+                    } else {
+                        SpecialFile::BlockDevice { major, minor }
+                    },
+                }
+            }
+            _ => unreachable!(), // excluded by the regex in NodesEntries
+        };
+        Ok(Some(SnapshotEntry {
+            path,
+            value,
+            metadata,
+        }))
+    }
+}
+
+// Reads records terminated by a fixed sequence of bytes, keeping track of where each starts for
+// error reporting.
+struct Records<T> {
+    reader: T,
+    file: &'static str,
+    record: u64, // of the most recent record, counting from 1
+    offset: u64, // of the most recent record
+    next_offset: u64,
+}
+
+impl<T: io::BufRead> Records<T> {
+    fn new(reader: T, file: &'static str) -> Self {
+        Self {
+            reader,
+            file,
+            record: 0,
+            offset: 0,
+            next_offset: 0,
+        }
+    }
+
+    fn next(&mut self, terminators: &[u8]) -> Result<Option<Vec<u8>>> {
+        if !self.reader.has_data_left()? {
+            return Ok(None);
+        }
+        self.record += 1;
+        self.offset = self.next_offset;
+        let mut buf = vec![];
+        for terminator in terminators {
+            self.reader.read_until(*terminator, &mut buf)?;
+            if buf.last() != Some(terminator) {
+                self.next_offset += buf.len() as u64;
+                let expected = if *terminator == 0 { "NUL" } else { "newline" };
+                return Err(self
+                    .error(SnapshotParseErrorKind::UnexpectedEof(expected))
+                    .into());
+            }
+        }
+        self.next_offset += buf.len() as u64;
+        Ok(Some(buf))
+    }
+
+    fn parse_field<F: FromStr>(
+        &self,
+        name: &'static str,
+        s: &str,
+    ) -> Result<F, SnapshotParseError> {
+        s.parse()
+            .map_err(|_| self.error(SnapshotParseErrorKind::MalformedField(name)))
+    }
+
+    fn error(&self, kind: SnapshotParseErrorKind) -> SnapshotParseError {
+        SnapshotParseError {
+            file: self.file,
+            record: self.record,
+            offset: self.offset,
+            kind,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct NodesEntry {
+    ty: char, // [dflcbsp]
+    mode: u16,
+    size: Option<u64>,
+    path: Vec<u8>,
+    target: Vec<u8>,
+    owner: Option<(u32, u32)>,
+    mtime: Option<(i64, i64)>,
+    rdev: Option<(u32, u32)>, // (major, minor), for device nodes only
+}
+
+impl NodesEntry {
+    fn is_executable(&self) -> bool {
+        self.mode & 0o100 != 0
+    }
+
+    fn metadata(&self) -> Option<NodeMetadata> {
+        let (uid, gid) = self.owner?;
+        Some(NodeMetadata {
+            mode: self.mode.into(),
+            uid,
+            gid,
+            mtime: self.mtime?,
+            link_group: None,
+        })
+    }
+}
+
+struct NodesEntries<T> {
+    records: Records<T>,
+}
+
+impl<T: io::BufRead> FallibleIterator for NodesEntries<T> {
+    type Item = NodesEntry;
+    type Error = Error;
+
+    fn next(&mut self) -> Result<Option<Self::Item>, Self::Error> {
+        const FORMAT: &str =
+            "<type> 0<mode> <size> <path>\\0 <target>\\0[ <uid> <gid> <mtime>[ <major> <minor>]]\\n";
+        lazy_static! {
+            static ref RE: Regex = Regex::new(
+                r"(?s-u)^(?P<type>[dflcbsp]) 0(?P<mode>[0-9]{3}[0-9]*) (?P<size>([0-9]+|\?)) (?P<path>.*)\x00 (?P<target>.*)\x00( (?P<uid>[0-9]+) (?P<gid>[0-9]+) (?P<mtime_sec>-?[0-9]+)\.(?P<mtime_nsec>[0-9]{9})( (?P<major>[0-9]+) (?P<minor>[0-9]+))?)?\n$"
+            )
+            .unwrap();
+        }
+        let buf = match self.records.next(&[0, 0, b'\n'])? {
+            Some(buf) => buf,
+            None => return Ok(None),
+        };
+        let records = &self.records;
+        let caps = RE
+            .captures(&buf)
+            .ok_or_else(|| records.error(SnapshotParseErrorKind::Malformed(FORMAT)))?;
+        // Apart from path and target, all fields are ASCII by construction of the regex.
+        let field = |name: &str| str::from_utf8(&caps[name]).unwrap();
+        let optional_field = |name: &str| {
+            caps.name(name)
+                .map(|m| str::from_utf8(m.as_bytes()).unwrap())
+        };
+        let size = match field("size") {
+            "?" => None,
+            s => Some(records.parse_field("size", s)?),
+        };
+        Ok(Some(NodesEntry {
+            ty: caps["type"][0].into(),
+            mode: u16::from_str_radix(field("mode"), 8)
+                .map_err(|_| records.error(SnapshotParseErrorKind::MalformedField("mode")))?,
+            size,
+            path: caps["path"].to_vec(),
+            target: caps["target"].to_vec(),
+            owner: match (optional_field("uid"), optional_field("gid")) {
+                (Some(uid), Some(gid)) => Some((
+                    records.parse_field("uid", uid)?,
+                    records.parse_field("gid", gid)?,
+                )),
+                _ => None,
+            },
+            mtime: match (optional_field("mtime_sec"), optional_field("mtime_nsec")) {
+                (Some(sec), Some(nsec)) => Some((
+                    records.parse_field("mtime", sec)?,
+                    records.parse_field("mtime", nsec)?,
+                )),
+                _ => None,
+            },
+            rdev: match (optional_field("major"), optional_field("minor")) {
+                (Some(major), Some(minor)) => Some((
+                    records.parse_field("major", major)?,
+                    records.parse_field("minor", minor)?,
+                )),
+                _ => None,
+            },
+        }))
+    }
+}
+
+#[derive(Debug)]
+struct DigestsEntry {
+    digest: String,
+    path: Vec<u8>,
+}
+
+struct DigestsEntries<T> {
+    records: Records<T>,
+}
+
+impl<T: io::BufRead> FallibleIterator for DigestsEntries<T> {
+    type Item = DigestsEntry;
+    type Error = Error;
+
+    fn next(&mut self) -> Result<Option<Self::Item>, Self::Error> {
+        const FORMAT: &str = "<digest> *<path>\\0\\n";
+        lazy_static! {
+            static ref RE: Regex =
+                Regex::new(r"(?s-u)^(?P<digest>[a-z0-9]{64}|[?]{64}) \*(?P<path>.*)\x00\n$")
+                    .unwrap();
+        }
+        let buf = match self.records.next(&[0, b'\n'])? {
+            Some(buf) => buf,
+            None => return Ok(None),
+        };
+        let caps = RE.captures(&buf).ok_or_else(|| {
+            self.records
+                .error(SnapshotParseErrorKind::Malformed(FORMAT))
+        })?;
+        Ok(Some(DigestsEntry {
+            digest: str::from_utf8(&caps["digest"]).unwrap().to_string(),
+            path: caps["path"].to_vec(),
+        }))
+    }
+}
+
+#[derive(Error, Debug)]
+#[error("{file}: record {record} at byte {offset}: {kind}")]
+pub struct SnapshotParseError {
+    pub file: &'static str,
+    pub record: u64,
+    pub offset: u64,
+    pub kind: SnapshotParseErrorKind,
+}
+
+#[derive(Error, Debug)]
+pub enum SnapshotParseErrorKind {
+    #[error("unexpected end of file, expected {0}")]
+    UnexpectedEof(&'static str),
+    #[error("malformed record, expected {0}")]
+    Malformed(&'static str),
+    #[error("malformed {0}")]
+    MalformedField(&'static str),
+    #[error("missing device number")]
+    MissingDeviceNumber,
+    #[error("unexpected end of file, expected digest for {0:?}")]
+    MissingDigest(String),
+    #[error("expected digest for {expected:?}, found digest for {found:?}")]
+    DigestPathMismatch { expected: String, found: String },
+    #[error("digest without a corresponding file")]
+    ExtraDigest,
+    #[error("root after the first node")]
+    MisplacedRoot,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entries<'a>(nodes: &'a [u8], digests: &'a [u8]) -> SnapshotEntries<&'a [u8]> {
+        SnapshotEntries {
+            nodes_entries: NodesEntries {
+                records: Records::new(nodes, "nodes"),
+            },
+            digests_entries: DigestsEntries {
+                records: Records::new(digests, "digests"),
+            },
+            changed: BTreeSet::new(),
+        }
+    }
+
+    fn parse_error(nodes: &[u8], digests: &[u8]) -> SnapshotParseError {
+        let mut entries = entries(nodes, digests);
+        loop {
+            match entries.next() {
+                Ok(Some(_)) => continue,
+                Ok(None) => panic!("no error"),
+                Err(err) => return err.downcast().unwrap(),
+            }
+        }
+    }
+
+    const DIGEST: &str = "da60ed9cad3849231c91f0419c8eb59d10d0ccf3fdfa7341fa6f657b684ba1cf";
+
+    #[test]
+    fn malformed() {
+        let nodes = b"d 0755 0 \0 \0\nf 0644 5 a\0 \0\n";
+        let digests = format!("{} *a\0\n", DIGEST);
+        assert_eq!(entries(nodes, digests.as_bytes()).count().unwrap(), 2);
+
+        let err = parse_error(b"d 0755 0 \0 \0\nf 0644 5 a\0 ", b"");
+        assert_eq!((err.file, err.record, err.offset), ("nodes", 2, 13));
+        assert!(matches!(
+            err.kind,
+            SnapshotParseErrorKind::UnexpectedEof("NUL")
+        ));
+
+        let err = parse_error(b"d 0755 0 \0 \0\nx 0644 5 a\0 \0\n", b"");
+        assert_eq!((err.record, err.offset), (2, 13));
+        assert!(matches!(err.kind, SnapshotParseErrorKind::Malformed(_)));
+
+        let err = parse_error(b"d 0755 0 \0 \0\nd 0755 0 \0 \0\n", b"");
+        assert_eq!((err.record, err.offset), (2, 13));
+        assert!(matches!(err.kind, SnapshotParseErrorKind::MisplacedRoot));
+
+        let err = parse_error(nodes, b"");
+        assert_eq!((err.file, err.record), ("digests", 0));
+        assert!(matches!(err.kind, SnapshotParseErrorKind::MissingDigest(_)));
+
+        let err = parse_error(nodes, format!("{} *b\0\n", DIGEST).as_bytes());
+        assert!(matches!(
+            err.kind,
+            SnapshotParseErrorKind::DigestPathMismatch { .. }
+        ));
+
+        let err = parse_error(nodes, format!("{0} *a\0\n{0} *b\0\n", DIGEST).as_bytes());
+        assert_eq!((err.file, err.record, err.offset), ("digests", 2, 69));
+        assert!(matches!(err.kind, SnapshotParseErrorKind::ExtraDigest));
+    }
+
+    #[test]
+    fn changed() {
+        let nodes = b"d 0755 0 \0 \0\nf 0644 5 a\0 \0\nf 0644 5 b\0 \0\n";
+        let digests = format!("{0} *a\0\n{0} *b\0\n", DIGEST);
+        let mut entries = entries(nodes, digests.as_bytes());
+        entries
+            .changed
+            .insert(ShadowPath::from_bytes(b"a").unwrap());
+        let paths = entries
+            .map(|entry| Ok(entry.path.to_string()))
+            .collect::<Vec<_>>()
+            .unwrap();
+        assert_eq!(paths, ["", "b"]);
+    }
+}
@@ -0,0 +1,95 @@
+use std::collections::BTreeSet;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::os::unix::ffi::OsStringExt;
+use std::path::{Path, PathBuf};
+
+use anyhow::{anyhow, Result};
+
+// The mount points of the mount namespace, from /proc/self/mountinfo. Unlike a change of device
+// number, this also tells bind mounts of a filesystem within itself.
+#[derive(Debug, Default)]
+pub struct MountPoints {
+    paths: BTreeSet<PathBuf>,
+}
+
+impl MountPoints {
+    const MOUNTINFO: &'static str = "/proc/self/mountinfo";
+
+    // Without /proc, only mounts of other filesystems can be told, by device number.
+    pub fn load() -> Result<Self> {
+        match fs::read(Self::MOUNTINFO) {
+            Ok(mountinfo) => Self::parse(&mountinfo),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                log::warn!(
+                    "{} is missing, so bind mounts go unnoticed",
+                    Self::MOUNTINFO
+                );
+                Ok(Self::default())
+            }
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    // Line format: "<id> <parent id> <major>:<minor> <root> <mount point> <options> ...", with
+    // spaces, tabs, newlines and backslashes in paths escaped in octal.
+    fn parse(mountinfo: &[u8]) -> Result<Self> {
+        let mut paths = BTreeSet::new();
+        for line in mountinfo.split(|b| *b == b'\n') {
+            if line.is_empty() {
+                continue;
+            }
+            let field = line
+                .split(|b| *b == b' ')
+                .nth(4)
+                .ok_or_else(|| anyhow!("malformed line in {}", Self::MOUNTINFO))?;
+            paths.insert(PathBuf::from(OsString::from_vec(unescape(field)?)));
+        }
+        Ok(Self { paths })
+    }
+
+    // `path` must be canonical, as mount points are recorded.
+    pub fn contains(&self, path: &Path) -> bool {
+        self.paths.contains(path)
+    }
+}
+
+fn unescape(field: &[u8]) -> Result<Vec<u8>> {
+    let mut unescaped = vec![];
+    let mut rest = field;
+    while let Some((&b, tail)) = rest.split_first() {
+        if b == b'\\' {
+            let octal = tail
+                .get(..3)
+                .and_then(|octal| std::str::from_utf8(octal).ok())
+                .and_then(|octal| u8::from_str_radix(octal, 8).ok())
+                .ok_or_else(|| anyhow!("malformed escape in {}", MountPoints::MOUNTINFO))?;
+            unescaped.push(octal);
+            rest = &tail[3..];
+        } else {
+            unescaped.push(b);
+            rest = tail;
+        }
+    }
+    Ok(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        let mount_points = MountPoints::parse(
+            b"23 28 0:22 / /proc rw,relatime - proc proc rw\n\
+              41 28 8:1 /home/a\\040b /mnt/a\\040b rw - ext4 /dev/sda1 rw\n",
+        )
+        .unwrap();
+        assert!(mount_points.contains(Path::new("/proc")));
+        assert!(mount_points.contains(Path::new("/mnt/a b")));
+        assert!(!mount_points.contains(Path::new("/home/a b")));
+        assert!(MountPoints::parse(b"23 28 0:22 /\n").is_err());
+        assert!(MountPoints::parse(b"23 28 0:22 / /a\\04 rw\n").is_err());
+    }
+}
@@ -0,0 +1,170 @@
+use std::collections::BTreeMap;
+use std::ffi::OsStr;
+use std::fs::{self, File, Metadata};
+use std::io::{self, BufRead, BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::str;
+
+use anyhow::{anyhow, bail, Result};
+
+use crate::ContentSha256;
+
+// Stat information that is expected to change whenever a file's content does. Compared against
+// the stat cache to decide whether a digest from a reference tree can be reused.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileStat {
+    pub size: u64,
+    pub mtime: (i64, i64),
+    pub ctime: (i64, i64),
+    pub ino: u64,
+    pub dev: u64,
+}
+
+impl FileStat {
+    pub fn from_metadata(metadata: &Metadata) -> Self {
+        Self {
+            size: metadata.size(),
+            mtime: (metadata.mtime(), metadata.mtime_nsec()),
+            ctime: (metadata.ctime(), metadata.ctime_nsec()),
+            ino: metadata.ino(),
+            dev: metadata.dev(),
+        }
+    }
+}
+
+// Each file's stat when it was last hashed, along with the digest it had then. The cache is written
+// by every snapshot, planted or not, so a stat match only vouches for the digest recorded with it.
+#[derive(Debug, Default)]
+pub struct StatCache {
+    entries: BTreeMap<PathBuf, (FileStat, ContentSha256)>,
+}
+
+impl StatCache {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn get(&self, path: &Path) -> Option<(&FileStat, &ContentSha256)> {
+        self.entries.get(path).map(|(stat, digest)| (stat, digest))
+    }
+
+    pub fn insert(&mut self, path: PathBuf, stat: FileStat, digest: ContentSha256) {
+        self.entries.insert(path, (stat, digest));
+    }
+
+    // A missing cache file is treated as an empty cache.
+    pub fn load(path: &Path) -> Result<Self> {
+        let mut reader = match File::open(path) {
+            Ok(file) => io::BufReader::new(file),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::new()),
+            Err(err) => return Err(err.into()),
+        };
+        let mut cache = Self::new();
+        let mut buf = vec![];
+        loop {
+            buf.clear();
+            if reader.read_until(b'\n', &mut buf)? == 0 {
+                break;
+            }
+            let (path, stat, digest) = parse_line(&buf)?;
+            cache.insert(path, stat, digest);
+        }
+        Ok(cache)
+    }
+
+    pub fn store(&self, path: &Path) -> Result<()> {
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let mut writer = BufWriter::new(File::create(&tmp)?);
+        for (path, (stat, digest)) in &self.entries {
+            write!(
+                writer,
+                "{} {} {} {}.{:09} {}.{:09} {} ",
+                stat.dev,
+                stat.ino,
+                stat.size,
+                stat.mtime.0,
+                stat.mtime.1,
+                stat.ctime.0,
+                stat.ctime.1,
+                digest
+            )?;
+            writer.write_all(path.as_os_str().as_bytes())?;
+            writer.write_all(b"\0\n")?;
+        }
+        writer.flush()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+}
+
+// Line format: "<dev> <ino> <size> <mtime>.<nsec> <ctime>.<nsec> <digest> <path>\0\n"
+fn parse_line(line: &[u8]) -> Result<(PathBuf, FileStat, ContentSha256)> {
+    let line = match line.strip_suffix(b"\0\n") {
+        Some(line) => line,
+        None => bail!("malformed stat cache line"),
+    };
+    let mut fields = line.splitn(7, |b| *b == b' ');
+    let mut next_field = || match fields.next() {
+        Some(field) => Ok(field),
+        None => Err(anyhow!("malformed stat cache line")),
+    };
+    let dev = str::from_utf8(next_field()?)?.parse()?;
+    let ino = str::from_utf8(next_field()?)?.parse()?;
+    let size = str::from_utf8(next_field()?)?.parse()?;
+    let mtime = parse_timestamp(next_field()?)?;
+    let ctime = parse_timestamp(next_field()?)?;
+    let digest = str::from_utf8(next_field()?)?.parse()?;
+    let path = PathBuf::from(OsStr::from_bytes(next_field()?));
+    Ok((
+        path,
+        FileStat {
+            size,
+            mtime,
+            ctime,
+            ino,
+            dev,
+        },
+        digest,
+    ))
+}
+
+fn parse_timestamp(field: &[u8]) -> Result<(i64, i64)> {
+    let field = str::from_utf8(field)?;
+    match field.split_once('.') {
+        Some((sec, nsec)) => Ok((sec.parse()?, nsec.parse()?)),
+        None => bail!("malformed timestamp in stat cache: {:?}", field),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "da60ed9cad3849231c91f0419c8eb59d10d0ccf3fdfa7341fa6f657b684ba1cf";
+
+    #[test]
+    fn line() {
+        let line = format!("2049 1234 5 1634567890.000000123 -1.5 {} a b/c\0\n", DIGEST);
+        let (path, stat, digest) = parse_line(line.as_bytes()).unwrap();
+        assert_eq!(path, Path::new("a b/c"));
+        assert_eq!(
+            stat,
+            FileStat {
+                size: 5,
+                mtime: (1634567890, 123),
+                ctime: (-1, 5),
+                ino: 1234,
+                dev: 2049,
+            }
+        );
+        assert_eq!(digest.to_string(), DIGEST);
+        assert!(parse_line(b"2049 1234 5 1634567890.0 -1.5 a\0\n").is_err());
+        let line = format!("2049 1234 5 1634567890.0 -1.5 {} a\n", DIGEST);
+        assert!(parse_line(line.as_bytes()).is_err());
+        let line = format!("2049 1234 5 1634567890 -1.5 {} a\0\n", DIGEST);
+        assert!(parse_line(line.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,1093 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::fs::{self, File, FileType, Metadata};
+use std::io::{self, BufReader, BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{FileTypeExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::thread;
+
+use anyhow::{bail, Error, Result};
+use fallible_iterator::FallibleIterator;
+
+use super::ignore::IgnoreRules;
+use super::mounts::MountPoints;
+use super::stat_cache::{FileStat, StatCache};
+use super::{
+    join_subject, DigestsEntries, NodesEntries, Records, SnapshotParseError, SnapshotParseErrorKind,
+};
+use crate::substance::sha256sum_rust;
+use crate::{ContentSha256, Progress, Shadow, Snapshot};
+
+#[derive(Clone, Debug)]
+pub struct TakeSnapshotOptions {
+    pub jobs: usize,
+    // Shadows by relative path from a previous snapshot of the same subject. Their digests are
+    // reused for files whose stat matches the entry in `stat_cache`, if it was cached along with
+    // the same digest.
+    pub reference: Option<BTreeMap<PathBuf, Shadow>>,
+    pub stat_cache: Option<PathBuf>,
+    // Gitignore-style patterns, applied after those in the subject's .keepignore.
+    pub excludes: Vec<String>,
+    // Continue an interrupted snapshot in the same directory instead of refusing to overwrite it.
+    pub resume: bool,
+    // Directories on other filesystems than the subject are recorded, but not descended into.
+    pub one_file_system: bool,
+    // Mount points are left out altogether.
+    pub skip_mountpoints: bool,
+    pub symlinks: SymlinkPolicy,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymlinkPolicy {
+    // Every symlink is recorded as a link.
+    Preserve,
+    // Symlinks to directories are walked as if they were the directories themselves, unless they
+    // lead back to a directory being walked.
+    FollowDirectories,
+}
+
+impl fmt::Display for SymlinkPolicy {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(match self {
+            Self::Preserve => "preserve",
+            Self::FollowDirectories => "follow-dirs",
+        })
+    }
+}
+
+impl FromStr for SymlinkPolicy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "preserve" => Self::Preserve,
+            "follow-dirs" => Self::FollowDirectories,
+            _ => bail!("unknown symlink policy '{}'", s),
+        })
+    }
+}
+
+impl Default for TakeSnapshotOptions {
+    fn default() -> Self {
+        Self {
+            jobs: 1,
+            reference: None,
+            stat_cache: None,
+            excludes: vec![],
+            resume: false,
+            one_file_system: false,
+            skip_mountpoints: false,
+            symlinks: SymlinkPolicy::Preserve,
+        }
+    }
+}
+
+impl<'a> Snapshot<'a> {
+    // `on_progress` is called as files are hashed, in the order of `nodes`.
+    pub fn take(
+        &self,
+        subject: &Path,
+        options: &TakeSnapshotOptions,
+        mut on_progress: impl FnMut(&Progress),
+    ) -> Result<()> {
+        // A single file makes a snapshot whose root is that file.
+        if !subject.is_dir() && !subject.is_file() {
+            bail!(
+                "'{}' is neither a directory nor a regular file",
+                subject.display()
+            );
+        }
+        // A subject that is a symlink is followed once, here, so that every later look at the
+        // root, when walking, hashing or storing it, sees the same node.
+        let subject = &fs::canonicalize(subject)?;
+        let resuming = options.resume && self.path().exists();
+        if resuming {
+            self.ensure_resumable(subject, options)?;
+        } else {
+            if self.path().exists() {
+                bail!("'{}' already exists", self.path().display());
+            }
+
+            fs::create_dir(self.path())?;
+
+            let mut subject_file = File::create(self.subject_path())?;
+            subject_file.write_all(subject.as_os_str().as_bytes())?;
+            subject_file.write_all(b"\n")?;
+
+            fs::write(self.options_path(), walk_options(options))?;
+        }
+
+        let mut ignore_rules = IgnoreRules::new();
+        let ignore_file = subject.join(IgnoreRules::FILE_NAME);
+        if ignore_file.is_file() {
+            ignore_rules.add_file(&ignore_file)?;
+        }
+        for pattern in &options.excludes {
+            ignore_rules.add(pattern.as_bytes())?;
+        }
+
+        // When resuming, the subject is walked again, and must hold the same nodes as the listing
+        // in `nodes`, though files among them may have been modified since.
+        let (nodes_path, files_path) = if resuming {
+            (
+                self.path().join("nodes.resume"),
+                self.path().join("files.resume"),
+            )
+        } else {
+            (self.nodes_path(), self.files_path())
+        };
+        let mut walker = Walker {
+            subject,
+            ignore_rules,
+            nodes: BufWriter::new(File::create(&nodes_path)?),
+            files: BufWriter::new(File::create(&files_path)?),
+            file_paths: vec![],
+            file_stats: vec![],
+            file_links: vec![],
+            inodes: BTreeMap::new(),
+            one_file_system: options.one_file_system,
+            mount_points: if options.skip_mountpoints {
+                Some(MountPoints::load()?)
+            } else {
+                None
+            },
+            symlinks: options.symlinks,
+            root_dev: 0,
+            ancestors: vec![],
+        };
+        walker.walk_root()?;
+        walker.nodes.flush()?;
+        walker.files.flush()?;
+        let checkpoint = if resuming {
+            let modified = match self.modified_since_listing(&nodes_path) {
+                Ok(modified) => modified,
+                Err(err) => {
+                    fs::remove_file(&nodes_path)?;
+                    fs::remove_file(&files_path)?;
+                    return Err(err);
+                }
+            };
+            let mut checkpoint = self.read_checkpoint(&walker.file_paths)?;
+            for (digest, modified) in checkpoint.iter_mut().zip(modified) {
+                if modified {
+                    *digest = None;
+                }
+            }
+            log::info!(
+                "resuming after {} of {} files, of which {} will be hashed again",
+                checkpoint.len(),
+                walker.file_paths.len(),
+                checkpoint.iter().filter(|digest| digest.is_none()).count()
+            );
+            checkpoint
+        } else {
+            vec![]
+        };
+
+        let old_stat_cache = match &options.stat_cache {
+            Some(path) => StatCache::load(path)?,
+            None => StatCache::new(),
+        };
+        let mut new_stat_cache = StatCache::new();
+        let mut reused = 0;
+        let files = walker
+            .file_paths
+            .into_iter()
+            .zip(walker.file_stats)
+            .zip(walker.file_links)
+            .enumerate()
+            .map(|(i, ((path, stat), link_of))| {
+                let digest = options
+                    .reference
+                    .as_ref()
+                    .and_then(|reference| reference.get(&path))
+                    .filter(|shadow| {
+                        shadow.size() == Some(stat.size)
+                            && old_stat_cache.get(&path) == Some((&stat, shadow.content_hash()))
+                    })
+                    .map(|shadow| shadow.content_hash().clone())
+                    .or_else(|| checkpoint.get(i).cloned().flatten());
+                if digest.is_some() {
+                    reused += 1;
+                }
+                PendingFile {
+                    path,
+                    stat,
+                    digest,
+                    link_of,
+                }
+            })
+            .collect::<Vec<_>>();
+        if options.reference.is_some() {
+            log::info!(
+                "reusing {} of {} digests from reference",
+                reused,
+                files.len()
+            );
+        }
+
+        let files = Arc::new(files);
+        // A resumed snapshot writes its digests again from the start, passing the checkpointed
+        // ones through, so that files modified since can be hashed again in their place. Until
+        // it is done, the checkpoint it resumed from stays in place for the next attempt.
+        let (digests_path, changed_path) = if resuming {
+            (
+                self.path().join("digests.resume"),
+                self.path().join("changed.resume"),
+            )
+        } else {
+            (self.digests_path(), self.changed_path())
+        };
+        let mut digests = BufWriter::new(File::create(&digests_path)?);
+        let mut changed = BufWriter::new(File::create(&changed_path)?);
+        let mut changed_count = 0;
+        let mut progress = Progress::new(
+            files.len() as u64,
+            files.iter().map(|file| file.stat.size).sum(),
+        );
+        on_progress(&progress);
+        hash_files(subject, &files, options.jobs, |i, digest, is_changed| {
+            progress.add_file(files[i].stat.size);
+            on_progress(&progress);
+            if !is_changed {
+                new_stat_cache.insert(files[i].path.clone(), files[i].stat.clone(), digest.clone());
+            }
+            // Marked before its digest is checkpointed, so that a resumed snapshot never trusts
+            // the digest of a changed file.
+            if is_changed {
+                log::warn!(
+                    "'{}' changed while the snapshot was taken",
+                    files[i].path.display()
+                );
+                changed.write_all(files[i].path.as_os_str().as_bytes())?;
+                changed.write_all(b"\0")?;
+                changed.flush()?;
+                changed_count += 1;
+            }
+            write_digests_entry(&mut digests, &digest, &files[i].path)?;
+            // Each complete entry in `digests` is a checkpoint for `resume`.
+            digests.flush()?;
+            Ok(())
+        })?;
+        drop((digests, changed));
+        if resuming {
+            // The old digests go first, as they may be of files listed in the new `changed` but
+            // not the old. Interrupted before the rest, the next attempt hashes every file again.
+            match fs::remove_file(self.digests_path()) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err.into()),
+                _ => {}
+            }
+            fs::rename(&changed_path, self.changed_path())?;
+            fs::rename(&digests_path, self.digests_path())?;
+            fs::rename(&nodes_path, self.nodes_path())?;
+            fs::rename(&files_path, self.files_path())?;
+        }
+        if changed_count > 0 {
+            log::warn!(
+                "leaving {} changed files out of the snapshot",
+                changed_count
+            );
+        }
+
+        let mut sha256sum = BufWriter::new(File::create(self.sha256sum_path())?);
+        for path in &[
+            self.nodes_path(),
+            self.digests_path(),
+            self.changed_path(),
+            self.options_path(),
+        ] {
+            let digest = sha256sum_rust(path)?;
+            write!(sha256sum, "{} *", digest)?;
+            sha256sum.write_all(path.as_os_str().as_bytes())?;
+            sha256sum.write_all(b"\n")?;
+        }
+        sha256sum.flush()?;
+
+        if let Some(path) = &options.stat_cache {
+            new_stat_cache.store(path)?;
+        }
+
+        Ok(())
+    }
+
+    // A snapshot can be resumed until its sha256sum.txt is written, as long as it is of the same
+    // subject, walked in the same way.
+    fn ensure_resumable(&self, subject: &Path, options: &TakeSnapshotOptions) -> Result<()> {
+        if self.sha256sum_path().exists() {
+            bail!("snapshot '{}' is already complete", self.path().display());
+        }
+        for path in &[
+            self.subject_path(),
+            self.options_path(),
+            self.nodes_path(),
+            self.files_path(),
+        ] {
+            if !path.is_file() {
+                bail!(
+                    "cannot resume snapshot '{}': missing '{}'",
+                    self.path().display(),
+                    path.display()
+                );
+            }
+        }
+        if self.subject()? != subject {
+            bail!(
+                "cannot resume snapshot '{}' of {} with {}",
+                self.path().display(),
+                self.subject()?.display(),
+                subject.display()
+            );
+        }
+        if fs::read_to_string(self.options_path())? != walk_options(options) {
+            bail!(
+                "cannot resume snapshot '{}' with different options than it was started with",
+                self.path().display()
+            );
+        }
+        Ok(())
+    }
+
+    // Compares the listing of a resumed walk with the one the snapshot was started with. They must
+    // be of the same nodes, but each file may have been modified since, which is told by its size
+    // and mtime, so that its checkpointed digest is not trusted.
+    fn modified_since_listing(&self, nodes_path: &Path) -> Result<Vec<bool>> {
+        let open = |path: &Path| -> Result<_> {
+            Ok(NodesEntries {
+                records: Records::new(BufReader::new(File::open(path)?), "nodes"),
+            })
+        };
+        let mut listed = open(&self.nodes_path())?;
+        let mut walked = open(nodes_path)?;
+        let mut modified = vec![];
+        loop {
+            match (listed.next()?, walked.next()?) {
+                (None, None) => return Ok(modified),
+                (Some(listed), Some(walked))
+                    if listed.ty == walked.ty && listed.path == walked.path =>
+                {
+                    if walked.ty == 'f' {
+                        modified.push(listed.size != walked.size || listed.mtime != walked.mtime);
+                    }
+                }
+                (_, walked) => bail!(
+                    "'{}' has changed since snapshot '{}' was started{}",
+                    self.subject()?.display(),
+                    self.path().display(),
+                    match walked {
+                        Some(walked) => format!(", at '{}'", String::from_utf8_lossy(&walked.path)),
+                        None => String::new(),
+                    }
+                ),
+            }
+        }
+    }
+
+    // Reads the digests written before the interruption, up to the last complete entry. Files
+    // marked as changed have no digest to reuse.
+    fn read_checkpoint(&self, file_paths: &[PathBuf]) -> Result<Vec<Option<ContentSha256>>> {
+        if !self.digests_path().exists() {
+            return Ok(vec![]);
+        }
+        let changed = if self.changed_path().exists() {
+            fs::read(self.changed_path())?
+                .split_inclusive(|b| *b == 0)
+                .filter_map(|path| path.strip_suffix(b"\0").map(<[u8]>::to_vec))
+                .collect::<BTreeSet<_>>()
+        } else {
+            BTreeSet::new()
+        };
+        let mut entries = DigestsEntries {
+            records: Records::new(BufReader::new(File::open(self.digests_path())?), "digests"),
+        };
+        let mut checkpoint = vec![];
+        loop {
+            match entries.next() {
+                Ok(Some(entry)) => {
+                    let expected = file_paths.get(checkpoint.len());
+                    if expected.map(|path| path.as_os_str().as_bytes()) != Some(&entry.path) {
+                        bail!(entries
+                            .records
+                            .error(SnapshotParseErrorKind::DigestPathMismatch {
+                                expected: expected
+                                    .map(|path| path.display().to_string())
+                                    .unwrap_or_default(),
+                                found: String::from_utf8_lossy(&entry.path).into_owned(),
+                            }));
+                    }
+                    let digest = entries.records.parse_field("digest", &entry.digest)?;
+                    checkpoint.push(if changed.contains(&entry.path) {
+                        None
+                    } else {
+                        Some(digest)
+                    });
+                }
+                Ok(None) => break,
+                Err(err) => match err.downcast_ref::<SnapshotParseError>() {
+                    Some(SnapshotParseError {
+                        kind: SnapshotParseErrorKind::UnexpectedEof(_),
+                        ..
+                    }) => break,
+                    _ => return Err(err),
+                },
+            }
+        }
+        Ok(checkpoint)
+    }
+}
+
+struct Walker<'a> {
+    subject: &'a Path,
+    ignore_rules: IgnoreRules,
+    nodes: BufWriter<File>,
+    files: BufWriter<File>,
+    file_paths: Vec<PathBuf>,
+    file_stats: Vec<FileStat>,
+    // For each file, the index of an earlier hard link to the same inode.
+    file_links: Vec<Option<usize>>,
+    inodes: BTreeMap<(u64, u64), usize>,
+    one_file_system: bool,
+    // Only loaded to skip mount points.
+    mount_points: Option<MountPoints>,
+    symlinks: SymlinkPolicy,
+    root_dev: u64,
+    // Directories being walked, by (dev, ino), to avoid following a symlink into a loop.
+    ancestors: Vec<(u64, u64)>,
+}
+
+struct PendingFile {
+    path: PathBuf,
+    stat: FileStat,
+    digest: Option<ContentSha256>,
+    link_of: Option<usize>,
+}
+
+impl<'a> Walker<'a> {
+    fn walk_root(&mut self) -> Result<()> {
+        // Like find(1), the subject itself is the first node, with an empty relative path.
+        let metadata = fs::metadata(self.subject)?;
+        self.root_dev = metadata.dev();
+        self.walk(&mut PathBuf::new(), metadata)
+    }
+
+    fn walk(&mut self, relative_path: &mut PathBuf, metadata: Metadata) -> Result<()> {
+        let path = join_subject(self.subject, relative_path);
+        let ty = node_type(&metadata.file_type());
+        let inode = (metadata.dev(), metadata.ino());
+        let link_of = if ty == 'f' && metadata.nlink() > 1 {
+            self.inodes.get(&inode).copied()
+        } else {
+            None
+        };
+        // Like tar, a hard link records the path of the first link to the same inode as its target.
+        let target = if ty == 'l' {
+            fs::read_link(&path)?.into_os_string()
+        } else if let Some(i) = link_of {
+            self.file_paths[i].clone().into_os_string()
+        } else {
+            Default::default()
+        };
+
+        write!(
+            self.nodes,
+            "{} 0{:03o} {} ",
+            ty,
+            metadata.mode() & 0o7777,
+            metadata.len()
+        )?;
+        self.nodes.write_all(relative_path.as_os_str().as_bytes())?;
+        self.nodes.write_all(b"\0 ")?;
+        self.nodes.write_all(target.as_bytes())?;
+        write!(
+            self.nodes,
+            "\0 {} {} {}.{:09}",
+            metadata.uid(),
+            metadata.gid(),
+            metadata.mtime(),
+            metadata.mtime_nsec()
+        )?;
+        if ty == 'c' || ty == 'b' {
+            let rdev = metadata.rdev();
+            let (major, minor) = unsafe { (libc::major(rdev), libc::minor(rdev)) };
+            write!(self.nodes, " {} {}", major, minor)?;
+        }
+        self.nodes.write_all(b"\n")?;
+
+        match ty {
+            'f' => {
+                if link_of.is_none() && metadata.nlink() > 1 {
+                    self.inodes.insert(inode, self.file_paths.len());
+                }
+                self.files.write_all(relative_path.as_os_str().as_bytes())?;
+                self.files.write_all(b"\0")?;
+                self.file_paths.push(relative_path.clone());
+                self.file_stats.push(FileStat::from_metadata(&metadata));
+                self.file_links.push(link_of);
+            }
+            'd' if self.one_file_system && metadata.dev() != self.root_dev => {
+                log::info!(
+                    "not descending into {}, on another filesystem",
+                    path.display()
+                );
+            }
+            'd' => {
+                self.ancestors.push(inode);
+                let mut children = fs::read_dir(&path)?
+                    .map(|entry| entry.map(|entry| entry.file_name()))
+                    .collect::<Result<Vec<_>, _>>()?;
+                children.sort_by(|a, b| a.as_bytes().cmp(b.as_bytes()));
+                for child in children {
+                    relative_path.push(&child);
+                    let child_path = self.subject.join(&relative_path);
+                    let mut child_metadata = fs::symlink_metadata(&child_path)?;
+                    if child_metadata.file_type().is_symlink()
+                        && self.symlinks == SymlinkPolicy::FollowDirectories
+                    {
+                        child_metadata = self.follow(&child_path, child_metadata)?;
+                    }
+                    if self.ignore_rules.is_excluded(
+                        relative_path.as_os_str().as_bytes(),
+                        child_metadata.is_dir(),
+                    ) {
+                        log::debug!("excluding {}", relative_path.display());
+                    } else if child_metadata.is_dir()
+                        && self.is_mount_point(&child_path, &child_metadata, &metadata)?
+                    {
+                        log::info!("skipping mount point {}", child_path.display());
+                    } else {
+                        self.walk(relative_path, child_metadata)?;
+                    }
+                    relative_path.pop();
+                }
+                self.ancestors.pop();
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl<'a> Walker<'a> {
+    // A bind mount of a filesystem within itself keeps the device number of its parent, so it is
+    // only told by being listed as a mount point, under its canonical path, as it may have been
+    // reached through a followed symlink.
+    fn is_mount_point(&self, path: &Path, metadata: &Metadata, parent: &Metadata) -> Result<bool> {
+        Ok(match &self.mount_points {
+            Some(mount_points) => {
+                metadata.dev() != parent.dev() || mount_points.contains(&fs::canonicalize(path)?)
+            }
+            None => false,
+        })
+    }
+
+    // Returns the metadata of the directory a symlink leads to, or that of the symlink itself if it
+    // is dangling, leads to something else, or leads to a directory being walked.
+    fn follow(&self, path: &Path, link_metadata: Metadata) -> Result<Metadata> {
+        match fs::metadata(path) {
+            Ok(metadata) if metadata.is_dir() => {
+                if self.ancestors.contains(&(metadata.dev(), metadata.ino())) {
+                    log::warn!("not following {}, which loops", path.display());
+                    Ok(link_metadata)
+                } else {
+                    Ok(metadata)
+                }
+            }
+            _ => Ok(link_metadata),
+        }
+    }
+}
+
+// Recorded in the snapshot, so that a resumed snapshot walks its subject in the same way.
+fn walk_options(options: &TakeSnapshotOptions) -> String {
+    format!(
+        "one-file-system {}\nskip-mountpoints {}\nsymlinks {}\n",
+        options.one_file_system, options.skip_mountpoints, options.symlinks
+    )
+}
+
+fn node_type(file_type: &FileType) -> char {
+    if file_type.is_dir() {
+        'd'
+    } else if file_type.is_file() {
+        'f'
+    } else if file_type.is_symlink() {
+        'l'
+    } else if file_type.is_char_device() {
+        'c'
+    } else if file_type.is_block_device() {
+        'b'
+    } else if file_type.is_socket() {
+        's'
+    } else {
+        'p'
+    }
+}
+
+// Hashes files on `jobs` worker threads, but calls `on_digest` in the order of `files`, which is
+// the order in which `SnapshotEntries` expects to find them in `digests`. Files with a known
+// digest are passed through without being read, and hard links take the digest of the first link.
+fn hash_files(
+    subject: &Path,
+    files: &Arc<Vec<PendingFile>>,
+    jobs: usize,
+    mut on_digest: impl FnMut(usize, ContentSha256, bool) -> Result<()>,
+) -> Result<()> {
+    let link_targets = files
+        .iter()
+        .filter_map(|file| file.link_of)
+        .collect::<BTreeSet<_>>();
+    let next = Arc::new(AtomicUsize::new(0));
+    let abort = Arc::new(AtomicBool::new(false));
+    let (tx, rx) = mpsc::channel();
+    let workers = (0..jobs.max(1))
+        .map(|_| {
+            let subject = subject.to_path_buf();
+            let files = Arc::clone(files);
+            let next = Arc::clone(&next);
+            let abort = Arc::clone(&abort);
+            let tx = tx.clone();
+            thread::spawn(move || {
+                while !abort.load(Ordering::Relaxed) {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= files.len() {
+                        break;
+                    }
+                    let result = match (&files[i].digest, files[i].link_of) {
+                        (Some(digest), _) => Ok(Some((digest.clone(), false))),
+                        (None, Some(_)) => Ok(None),
+                        (None, None) => {
+                            hash_file(&join_subject(&subject, &files[i].path), &files[i].stat)
+                                .map(Some)
+                        }
+                    };
+                    if tx.send((i, result)).is_err() {
+                        break;
+                    }
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    drop(tx);
+
+    let mut pending = BTreeMap::new();
+    let mut link_digests = BTreeMap::<usize, (ContentSha256, bool)>::new();
+    let mut expected = 0;
+    let result = (|| {
+        for (i, digest) in rx.iter() {
+            pending.insert(i, digest?);
+            while let Some(digest) = pending.remove(&expected) {
+                // The first link precedes the others, so its digest is already known.
+                let digest = match digest {
+                    Some(digest) => digest,
+                    None => link_digests[&files[expected].link_of.unwrap()].clone(),
+                };
+                if link_targets.contains(&expected) {
+                    link_digests.insert(expected, digest.clone());
+                }
+                on_digest(expected, digest.0, digest.1)?;
+                expected += 1;
+            }
+        }
+        Ok(())
+    })();
+
+    abort.store(true, Ordering::Relaxed);
+    for worker in workers {
+        worker.join().unwrap();
+    }
+    result
+}
+
+// Hashes a file, and reports whether it was modified since it was listed, judging by its size and
+// mtime before and after it is read. The digest of a modified file is meaningless.
+fn hash_file(path: &Path, stat: &FileStat) -> Result<(ContentSha256, bool)> {
+    let unchanged = || -> Result<bool> {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) => {
+                let now = FileStat::from_metadata(&metadata);
+                Ok(now.size == stat.size && now.mtime == stat.mtime)
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(err) => Err(err.into()),
+        }
+    };
+    let changed = (ContentSha256::from_slice(&[0; 32]), true);
+    if !unchanged()? {
+        return Ok(changed);
+    }
+    let digest = sha256sum_rust(path);
+    if !unchanged()? {
+        return Ok(changed);
+    }
+    Ok((digest?, false))
+}
+
+fn write_digests_entry(w: &mut impl Write, digest: &ContentSha256, path: &Path) -> Result<()> {
+    write!(w, "{} *", digest)?;
+    w.write_all(path.as_os_str().as_bytes())?;
+    w.write_all(b"\0\n")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use git2::{Oid, Repository};
+
+    use super::*;
+    use crate::{
+        Database, FilesystemSubstance, ShadowPath, ShadowTreeEntryName, SnapshotEntryValue,
+        Substance, TreeMetadata,
+    };
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("keep-{}-{:016x}", name, rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn jobs() {
+        let dir = temp_dir("jobs");
+        let subject = dir.join("subject");
+        // Larger files come first, so that workers finish out of order.
+        for i in 0..40 {
+            let parent = subject.join((i % 4).to_string());
+            fs::create_dir_all(&parent).unwrap();
+            fs::write(parent.join(i.to_string()), vec![i as u8; (40 - i) * 50_000]).unwrap();
+        }
+        let digests = [1, 8]
+            .iter()
+            .map(|&jobs| {
+                let path = dir.join(format!("snapshot-{}", jobs));
+                let snapshot = Snapshot::new(&path);
+                let options = TakeSnapshotOptions {
+                    jobs,
+                    ..Default::default()
+                };
+                snapshot.take(&subject, &options, |_| {}).unwrap();
+                fs::read(snapshot.digests_path()).unwrap()
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(digests[0], digests[1]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    fn take(
+        subject: &Path,
+        path: &Path,
+        options: &TakeSnapshotOptions,
+    ) -> BTreeMap<PathBuf, Shadow> {
+        let snapshot = Snapshot::new(path);
+        snapshot.take(subject, options, |_| {}).unwrap();
+        snapshot
+            .entries()
+            .unwrap()
+            .filter_map(|entry| {
+                Ok(match entry.value {
+                    SnapshotEntryValue::File { shadow, .. } => {
+                        Some((entry.path.to_path_buf(), shadow))
+                    }
+                    _ => None,
+                })
+            })
+            .collect()
+            .unwrap()
+    }
+
+    #[test]
+    fn walk_options() {
+        let dir = temp_dir("walk-options");
+        let subject = dir.join("subject");
+        fs::create_dir_all(subject.join("d")).unwrap();
+        fs::write(subject.join("d/f"), "f").unwrap();
+        std::os::unix::fs::symlink("d", subject.join("link")).unwrap();
+        std::os::unix::fs::symlink(".", subject.join("loop")).unwrap();
+        // On another filesystem than any subject.
+        std::os::unix::fs::symlink("/proc", subject.join("proc")).unwrap();
+        let walk = |name: &str, options: &TakeSnapshotOptions| {
+            let path = dir.join(name);
+            let snapshot = Snapshot::new(&path);
+            snapshot.take(&subject, options, |_| {}).unwrap();
+            snapshot
+                .entries()
+                .unwrap()
+                .map(|entry| {
+                    let ty = match entry.value {
+                        SnapshotEntryValue::File { .. } => 'f',
+                        SnapshotEntryValue::Link { .. } => 'l',
+                        SnapshotEntryValue::Special { .. } => 's',
+                        SnapshotEntryValue::Tree => 'd',
+                    };
+                    Ok(format!("{} {}", ty, entry.path))
+                })
+                .collect::<Vec<_>>()
+                .unwrap()
+        };
+
+        assert_eq!(
+            walk("preserve", &TakeSnapshotOptions::default()),
+            ["d ", "d d", "f d/f", "l link", "l loop", "l proc"]
+        );
+        let follow = TakeSnapshotOptions {
+            symlinks: SymlinkPolicy::FollowDirectories,
+            one_file_system: true,
+            ..Default::default()
+        };
+        assert_eq!(
+            walk("one-file-system", &follow),
+            ["d ", "d d", "f d/f", "d link", "f link/f", "l loop", "d proc"]
+        );
+        let follow = TakeSnapshotOptions {
+            one_file_system: false,
+            skip_mountpoints: true,
+            ..follow
+        };
+        assert_eq!(
+            walk("skip-mountpoints", &follow),
+            ["d ", "d d", "f d/f", "d link", "f link/f", "l loop"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn stat_cache() {
+        let dir = temp_dir("stat-cache");
+        let subject = dir.join("subject");
+        fs::create_dir(&subject).unwrap();
+        fs::write(subject.join("x"), "old").unwrap();
+        let options = TakeSnapshotOptions {
+            stat_cache: Some(dir.join("stat-cache")),
+            ..Default::default()
+        };
+        let head = take(&subject, &dir.join("1"), &options);
+
+        // Edited in place at the same size, after a coarse timestamp tick, and snapshotted without
+        // being planted, which caches the new stat.
+        thread::sleep(Duration::from_millis(50));
+        fs::write(subject.join("x"), "new").unwrap();
+        take(&subject, &dir.join("2"), &options);
+
+        let options = TakeSnapshotOptions {
+            reference: Some(head),
+            ..options
+        };
+        let shadows = take(&subject, &dir.join("3"), &options);
+        assert_eq!(
+            shadows[Path::new("x")].content_hash(),
+            &sha256sum_rust(&subject.join("x")).unwrap()
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn store_changed() {
+        let dir = temp_dir("store-changed");
+        let subject = dir.join("subject");
+        fs::create_dir(&subject).unwrap();
+        for name in &["a", "b", "c"] {
+            fs::write(subject.join(name), name).unwrap();
+        }
+        let snapshot_path = dir.join("snapshot");
+        let snapshot = Snapshot::new(&snapshot_path);
+        snapshot
+            .take(&subject, &TakeSnapshotOptions::default(), |_| {})
+            .unwrap();
+        let db = Database::new(Repository::init_bare(dir.join("repo")).unwrap());
+        let (_, tree) = db.plant_snapshot(&snapshot).unwrap();
+
+        // Rewritten with the same content, and replaced by a directory.
+        thread::sleep(Duration::from_millis(50));
+        fs::write(subject.join("a"), "a").unwrap();
+        fs::remove_file(subject.join("b")).unwrap();
+        fs::create_dir(subject.join("b")).unwrap();
+
+        fs::create_dir_all(dir.join("substance/blobs")).unwrap();
+        fs::create_dir_all(dir.join("substance/partial")).unwrap();
+        let substance = FilesystemSubstance::new(dir.join("substance"));
+        let changed = db
+            .store_snapshot(&substance, tree, &subject, |_| {})
+            .unwrap();
+        let changed = changed.iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(changed, ["a", "b"]);
+        assert!(substance.have_blob(&sha256sum_rust(&subject.join("c")).unwrap()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn single_file_through_symlink() {
+        let dir = temp_dir("single-file");
+        fs::write(dir.join("file"), "content").unwrap();
+        std::os::unix::fs::symlink("file", dir.join("link")).unwrap();
+        let snapshot_path = dir.join("snapshot");
+        let snapshot = Snapshot::new(&snapshot_path);
+        snapshot
+            .take(&dir.join("link"), &TakeSnapshotOptions::default(), |_| {})
+            .unwrap();
+        let db = Database::new(Repository::init_bare(dir.join("repo")).unwrap());
+        let (_, shadow) = db.plant_snapshot(&snapshot).unwrap();
+
+        fs::create_dir_all(dir.join("substance/blobs")).unwrap();
+        fs::create_dir_all(dir.join("substance/partial")).unwrap();
+        let substance = FilesystemSubstance::new(dir.join("substance"));
+        let changed = db
+            .store_snapshot(&substance, shadow, &dir.join("link"), |_| {})
+            .unwrap();
+        assert!(changed.is_empty());
+        assert!(substance.have_blob(&sha256sum_rust(&dir.join("file")).unwrap()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn hard_links() {
+        let dir = temp_dir("hard-links");
+        let subject = dir.join("subject");
+        fs::create_dir_all(subject.join("d")).unwrap();
+        fs::write(subject.join("a"), "linked").unwrap();
+        fs::hard_link(subject.join("a"), subject.join("d/b")).unwrap();
+        fs::write(subject.join("c"), "linked").unwrap();
+
+        // Only the first link is read: the other would be taken to have changed if it were, as
+        // there is nothing at its path.
+        let stat = FileStat::from_metadata(&fs::metadata(subject.join("a")).unwrap());
+        let pending = |path: &str, link_of| PendingFile {
+            path: PathBuf::from(path),
+            stat: stat.clone(),
+            digest: None,
+            link_of,
+        };
+        let files = Arc::new(vec![pending("a", None), pending("missing", Some(0))]);
+        let mut digests = vec![];
+        hash_files(&subject, &files, 2, |i, digest, is_changed| {
+            assert!(!is_changed);
+            digests.push((i, digest));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(digests.len(), 2);
+        assert_eq!(digests[0].1, digests[1].1);
+
+        let snapshot_path = dir.join("snapshot");
+        let snapshot = Snapshot::new(&snapshot_path);
+        snapshot
+            .take(&subject, &TakeSnapshotOptions::default(), |_| {})
+            .unwrap();
+        let db = Database::new(Repository::init_bare(dir.join("repo")).unwrap());
+        let (_, tree) = db.plant_snapshot(&snapshot).unwrap();
+        let child = |name: &[u8]| ShadowPath::from_bytes(name).unwrap().components()[0].clone();
+        let link_group = |tree: Oid, name: &[u8]| {
+            let tree = db.repository().find_tree(tree).unwrap();
+            let marker = tree
+                .get_name(&ShadowTreeEntryName::encode_marker())
+                .unwrap();
+            let metadata =
+                TreeMetadata::from_bytes(db.repository().find_blob(marker.id()).unwrap().content())
+                    .unwrap();
+            metadata
+                .get(&ShadowTreeEntryName::Child(child(name)))
+                .unwrap()
+                .link_group
+        };
+        let d = db
+            .repository()
+            .find_tree(tree)
+            .unwrap()
+            .get_name(&ShadowTreeEntryName::encode_child(&child(b"d")))
+            .unwrap()
+            .id();
+        assert!(link_group(tree, b"a").is_some());
+        assert_eq!(link_group(tree, b"a"), link_group(d, b"b"));
+        assert_eq!(link_group(tree, b"c"), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    const DIGEST: &str = "da60ed9cad3849231c91f0419c8eb59d10d0ccf3fdfa7341fa6f657b684ba1cf";
+
+    #[test]
+    fn checkpoint() {
+        let dir = temp_dir("checkpoint");
+        let snapshot = Snapshot::new(&dir);
+        let paths = ["a", "b", "c"]
+            .iter()
+            .map(PathBuf::from)
+            .collect::<Vec<_>>();
+        fs::write(
+            snapshot.digests_path(),
+            format!("{0} *a\0\n{0} *b\0\n{0} *c", DIGEST),
+        )
+        .unwrap();
+        fs::write(snapshot.changed_path(), "b\0").unwrap();
+        let digest = DIGEST.parse::<ContentSha256>().unwrap();
+        assert_eq!(
+            snapshot.read_checkpoint(&paths).unwrap(),
+            [Some(digest), None]
+        );
+        assert!(snapshot.read_checkpoint(&paths[1..]).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resume() {
+        let dir = temp_dir("resume");
+        let subject = dir.join("subject");
+        fs::create_dir(&subject).unwrap();
+        for name in &["a", "b", "c"] {
+            fs::write(subject.join(name), name).unwrap();
+        }
+        let path = dir.join("snapshot");
+        let snapshot = Snapshot::new(&path);
+        let options = TakeSnapshotOptions {
+            resume: true,
+            ..Default::default()
+        };
+        snapshot.take(&subject, &options, |_| {}).unwrap();
+
+        // Interrupted while writing the digest of "c", with that of "b" replaced to tell whether
+        // it is hashed again, and "a" then modified at the same size.
+        let digests = fs::read(snapshot.digests_path()).unwrap();
+        let entry_len = digests.len() / 3;
+        let mut interrupted = digests[..entry_len].to_vec();
+        interrupted.extend_from_slice(format!("{} *b\0\n", DIGEST).as_bytes());
+        interrupted.extend_from_slice(&digests[2 * entry_len..2 * entry_len + 10]);
+        fs::write(snapshot.digests_path(), interrupted).unwrap();
+        fs::remove_file(snapshot.sha256sum_path()).unwrap();
+        // Then interrupted again, while resuming, which leaves the checkpoint as it was.
+        for name in &["nodes", "files", "digests", "changed"] {
+            fs::write(path.join(format!("{}.resume", name)), "partial").unwrap();
+        }
+        thread::sleep(Duration::from_millis(50));
+        fs::write(subject.join("a"), "A").unwrap();
+
+        snapshot.take(&subject, &options, |_| {}).unwrap();
+        snapshot.verify().unwrap();
+        let shadows = snapshot
+            .entries()
+            .unwrap()
+            .filter_map(|entry| {
+                Ok(match entry.value {
+                    SnapshotEntryValue::File { shadow, .. } => {
+                        Some(shadow.content_hash().to_string())
+                    }
+                    _ => None,
+                })
+            })
+            .collect::<Vec<_>>()
+            .unwrap();
+        let sha256sum = |name: &str| sha256sum_rust(&subject.join(name)).unwrap().to_string();
+        assert_eq!(
+            shadows,
+            [sha256sum("a"), DIGEST.to_string(), sha256sum("c")]
+        );
+
+        // A node that was not listed cannot be resumed past.
+        fs::remove_file(snapshot.sha256sum_path()).unwrap();
+        fs::write(subject.join("d"), "d").unwrap();
+        assert!(snapshot.take(&subject, &options, |_| {}).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,118 @@
+use std::fmt;
+use std::num::ParseIntError;
+use std::str::{self, FromStr, Utf8Error};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use thiserror::Error;
+
+// Planted in place of a shadow for nodes without content: named pipes, sockets, and device
+// nodes. Distinguished from shadows by the first word of the blob.
+#[derive(Clone, Debug, Hash, PartialOrd, Ord, PartialEq, Eq)]
+pub enum SpecialFile {
+    Fifo,
+    Socket,
+    CharDevice { major: u32, minor: u32 },
+    BlockDevice { major: u32, minor: u32 },
+}
+
+impl SpecialFile {
+    const PREFIX: &'static [u8] = b"special ";
+
+    pub fn is_special(content: &[u8]) -> bool {
+        content.starts_with(Self::PREFIX)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        self.to_string().as_bytes().to_vec()
+    }
+
+    pub fn from_bytes(content: &[u8]) -> Result<Self, SpecialFileError> {
+        let s = str::from_utf8(content).map_err(SpecialFileError::Utf8Error)?;
+        s.parse()
+    }
+}
+
+impl fmt::Display for SpecialFile {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::Fifo => write!(fmt, "special fifo\n"),
+            Self::Socket => write!(fmt, "special socket\n"),
+            Self::CharDevice { major, minor } => write!(fmt, "special char {} {}\n", major, minor),
+            Self::BlockDevice { major, minor } => {
+                write!(fmt, "special block {} {}\n", major, minor)
+            }
+        }
+    }
+}
+
+impl FromStr for SpecialFile {
+    type Err = SpecialFileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        lazy_static! {
+            static ref RE: Regex = Regex::new(
+                r"^special (?P<kind>fifo|socket|char|block)( (?P<major>[0-9]+) (?P<minor>[0-9]+))?\n$"
+            )
+            .unwrap();
+        }
+        let caps = RE.captures(s).ok_or(Self::Err::MalformedSpecialFile)?;
+        let device = match (caps.name("major"), caps.name("minor")) {
+            (Some(major), Some(minor)) => Some((major.as_str().parse()?, minor.as_str().parse()?)),
+            _ => None,
+        };
+        Ok(match (&caps["kind"], device) {
+            ("fifo", None) => Self::Fifo,
+            ("socket", None) => Self::Socket,
+            ("char", Some((major, minor))) => Self::CharDevice { major, minor },
+            ("block", Some((major, minor))) => Self::BlockDevice { major, minor },
+            _ => return Err(Self::Err::MalformedSpecialFile),
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum SpecialFileError {
+    #[error("malformed")]
+    MalformedSpecialFile,
+    #[error("error converting from utf-8: {0}")]
+    Utf8Error(
+        #[source]
+        #[from]
+        Utf8Error,
+    ),
+    #[error("malformed device number")]
+    MalformedDeviceNumber(
+        #[source]
+        #[from]
+        ParseIntError,
+    ),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ensure_err(s: &str) {
+        assert!(SpecialFile::from_str(s).is_err());
+    }
+
+    fn ensure_inverse(s: &str) {
+        assert_eq!(SpecialFile::from_str(s).unwrap().to_string(), s);
+    }
+
+    #[test]
+    fn special_file() {
+        ensure_err("");
+        ensure_err("special fifo");
+        ensure_err("special fifo 1 2\n");
+        ensure_err("special char\n");
+        ensure_err("special char 1\n");
+        ensure_inverse("special fifo\n");
+        ensure_inverse("special socket\n");
+        ensure_inverse("special char 1 3\n");
+        ensure_inverse("special block 8 16\n");
+        assert!(SpecialFile::is_special(b"special fifo\n"));
+        assert!(!SpecialFile::is_special(b"sha256 "));
+    }
+}
@@ -0,0 +1,431 @@
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::mem;
+use std::os::unix::fs::{FileExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{bail, Result};
+use sha2::{Digest, Sha256};
+
+use super::{
+    is_unreachable, seek_position, BlobReader, CopyMethod, DigestMismatch, FilesystemSubstance,
+    Substance,
+};
+use crate::{ContentSha256, Shadow};
+
+// Splits blobs into chunks at boundaries chosen by their content, so that revisions of a large
+// file that differ in a few places share most of their chunks, even where the differences shift
+// the rest of the content. Chunks are kept once each, as the blobs of a `FilesystemSubstance` in
+// the "chunks" subdirectory. A blob is kept as the index of its chunks, where it would be kept as
+// a plain file but with a ".chunks" suffix. Plain blobs, from before chunking was chosen, are
+// still read.
+pub struct ChunkedSubstance {
+    plain: FilesystemSubstance,
+    chunks: FilesystemSubstance,
+}
+
+// An index is of the digest and size of each chunk in turn.
+const INDEX_ENTRY_SIZE: usize = 32 + 8;
+
+impl ChunkedSubstance {
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            plain: FilesystemSubstance::new(path.as_ref()),
+            chunks: FilesystemSubstance::new(path.as_ref().join("chunks")),
+        }
+    }
+
+    fn index_path(&self, blob: &ContentSha256) -> PathBuf {
+        let mut path = self.plain.blob_file_path(blob).into_os_string();
+        path.push(".chunks");
+        path.into()
+    }
+
+    // Stores the chunks that are not kept yet, and writes the index to a new partial file.
+    // Returns the number of chunks stored along with the shadow.
+    fn write_partial(&self, partial_path: &Path, src: &mut dyn Read) -> Result<(Shadow, usize)> {
+        // Made by the first store rather than on opening, which may be for reading only.
+        fs::create_dir_all(self.chunks.blob_dir())?;
+        fs::create_dir_all(self.chunks.partial_dir())?;
+        let mut partial_file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(partial_path)?;
+        let result = (|| {
+            let mut hasher = Sha256::new();
+            let mut size = 0;
+            let mut index = vec![];
+            let mut stored = 0;
+            let mut chunker = Chunker::new(src);
+            while let Some(chunk) = chunker.next_chunk()? {
+                hasher.update(&chunk);
+                size += chunk.len() as u64;
+                let digest = ContentSha256::from_slice(&Sha256::digest(&chunk));
+                if !self.chunks.have_blob(&digest) {
+                    self.chunks.store_from_reader(&mut &chunk[..])?;
+                    stored += 1;
+                }
+                index.extend_from_slice(digest.as_bytes());
+                index.extend_from_slice(&(chunk.len() as u64).to_be_bytes());
+            }
+            partial_file.write_all(&index)?;
+            partial_file.set_permissions(Permissions::from_mode(0o444))?;
+            let shadow = Shadow::new(ContentSha256::from_slice(&hasher.finalize()), Some(size));
+            Ok((shadow, stored))
+        })();
+        if result.is_err() {
+            fs::remove_file(partial_path)?;
+        }
+        result
+    }
+
+    fn rename_into_place(&self, partial_path: &Path, blob: &ContentSha256) -> Result<()> {
+        let blob_parent = self.plain.blob_parent(blob);
+        if !blob_parent.exists() {
+            fs::create_dir(blob_parent)?;
+        }
+        fs::rename(partial_path, self.index_path(blob))?;
+        Ok(())
+    }
+
+    fn open_chunks(&self, index_path: &Path) -> Result<ChunkedBlobReader> {
+        let mut chunks = vec![];
+        let mut size = 0;
+        for (digest, chunk_size) in read_index(index_path)? {
+            chunks.push((size, self.chunks.blob_file_path(&digest)));
+            size += chunk_size;
+        }
+        Ok(ChunkedBlobReader {
+            chunks,
+            size,
+            position: 0,
+            current: None,
+        })
+    }
+}
+
+impl Substance for ChunkedSubstance {
+    fn store(&self, blob: &ContentSha256, src: &Path) -> Result<Option<CopyMethod>> {
+        if self.have_blob(blob) {
+            return Ok(None);
+        }
+
+        let partial_parent = self.plain.partial_parent(blob);
+        if !partial_parent.exists() {
+            fs::create_dir(&partial_parent)?;
+        }
+        let partial_path = self.plain.partial_path(blob);
+        let (shadow, stored) = self.write_partial(&partial_path, &mut File::open(src)?)?;
+        log::debug!(
+            "copied {} by {}, {} new chunks",
+            src.display(),
+            CopyMethod::Chunk,
+            stored
+        );
+        // Chunks already stored are left for gc.
+        if shadow.content_hash() != blob {
+            fs::remove_file(&partial_path)?;
+            bail!(DigestMismatch {
+                path: src.to_path_buf(),
+                expected: blob.clone(),
+                found: shadow.content_hash().clone(),
+            });
+        }
+        self.rename_into_place(&partial_path, blob)?;
+        Ok(Some(CopyMethod::Chunk))
+    }
+
+    fn store_from_reader(&self, src: &mut dyn Read) -> Result<Shadow> {
+        let partial_path = self
+            .plain
+            .partial_dir()
+            .join(format!("reader-{:016x}", rand::random::<u64>()));
+        let (shadow, _) = self.write_partial(&partial_path, src)?;
+        if self.have_blob(shadow.content_hash()) {
+            fs::remove_file(&partial_path)?;
+        } else if let Err(err) = self.rename_into_place(&partial_path, shadow.content_hash()) {
+            fs::remove_file(&partial_path)?;
+            return Err(err);
+        }
+        Ok(shadow)
+    }
+
+    fn have_blob(&self, blob: &ContentSha256) -> bool {
+        self.index_path(blob).is_file() || self.plain.have_blob(blob)
+    }
+
+    fn open_blob(&self, blob: &ContentSha256) -> Result<Box<dyn BlobReader>> {
+        let index_path = self.index_path(blob);
+        if !index_path.is_file() {
+            return self.plain.open_blob(blob);
+        }
+        Ok(Box::new(self.open_chunks(&index_path)?))
+    }
+
+    fn blob_path(&self, blob: &ContentSha256) -> Option<PathBuf> {
+        self.plain.blob_path(blob).filter(|path| path.is_file())
+    }
+
+    // Chunks are garbage once no index that is kept uses them, including the indexes spared for
+    // their age.
+    fn garbage(
+        &self,
+        reachable: &BTreeSet<ContentSha256>,
+        cutoff: SystemTime,
+    ) -> Result<Vec<PathBuf>> {
+        let mut garbage = self.plain.blob_garbage(cutoff, |name| {
+            is_unreachable(name.strip_suffix(".chunks").unwrap_or(name), reachable)
+        })?;
+        let garbage_indexes = garbage.iter().cloned().collect::<BTreeSet<_>>();
+        let mut used = BTreeSet::new();
+        for (name, path) in self.plain.blob_files()? {
+            if name.ends_with(".chunks") && !garbage_indexes.contains(&path) {
+                used.extend(read_index(&path)?.into_iter().map(|(digest, _)| digest));
+            }
+        }
+        if self.chunks.blob_dir().exists() {
+            garbage.extend(
+                self.chunks
+                    .blob_garbage(cutoff, |name| is_unreachable(name, &used))?,
+            );
+        }
+        Ok(garbage)
+    }
+}
+
+// The digest and size of each chunk.
+fn read_index(path: &Path) -> Result<Vec<(ContentSha256, u64)>> {
+    let index = fs::read(path)?;
+    if index.len() % INDEX_ENTRY_SIZE != 0 {
+        bail!("'{}' is not a chunk index", path.display());
+    }
+    Ok(index
+        .chunks(INDEX_ENTRY_SIZE)
+        .map(|entry| {
+            (
+                ContentSha256::from_slice(&entry[..32]),
+                u64::from_be_bytes(entry[32..].try_into().unwrap()),
+            )
+        })
+        .collect())
+}
+
+// Chunks are at least MIN_CHUNK_SIZE and at most MAX_CHUNK_SIZE bytes, and about a MiB on
+// average, which keeps indexes small while a change of a few bytes costs a few MiB.
+const MIN_CHUNK_SIZE: usize = 1 << 18;
+const MAX_CHUNK_SIZE: usize = 1 << 22;
+const BOUNDARY_MASK: u64 = (1 << 20) - 1;
+
+// Random values for the gear hash, from splitmix64. They must never change, or new chunks would
+// no longer match those already kept.
+const GEAR: [u64; 256] = gear_table();
+
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0; 256];
+    let mut state: u64 = 0x6b65_6570;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+// The gear hash at a byte depends on the 64 bytes up to it, so a boundary depends only on the
+// content just before it, and not on where the chunk started.
+fn find_boundary(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK_SIZE {
+        return data.len();
+    }
+    let mut hash = 0u64;
+    for (i, &byte) in data.iter().enumerate().skip(MIN_CHUNK_SIZE - 64) {
+        hash = (hash << 1).wrapping_add(GEAR[usize::from(byte)]);
+        if i >= MIN_CHUNK_SIZE && hash & BOUNDARY_MASK == 0 {
+            return i + 1;
+        }
+    }
+    data.len()
+}
+
+struct Chunker<'a> {
+    src: &'a mut dyn Read,
+    buf: Vec<u8>,
+    eof: bool,
+}
+
+impl<'a> Chunker<'a> {
+    fn new(src: &'a mut dyn Read) -> Self {
+        Self {
+            src,
+            buf: Vec::with_capacity(MAX_CHUNK_SIZE),
+            eof: false,
+        }
+    }
+
+    fn next_chunk(&mut self) -> io::Result<Option<Vec<u8>>> {
+        while !self.eof && self.buf.len() < MAX_CHUNK_SIZE {
+            let len = self.buf.len();
+            self.buf.resize(MAX_CHUNK_SIZE, 0);
+            match self.src.read(&mut self.buf[len..]) {
+                Ok(n) => {
+                    self.buf.truncate(len + n);
+                    self.eof = n == 0;
+                }
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => self.buf.truncate(len),
+                Err(err) => return Err(err),
+            }
+        }
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        let rest = self.buf.split_off(find_boundary(&self.buf));
+        Ok(Some(mem::replace(&mut self.buf, rest)))
+    }
+}
+
+// Reads the chunk that holds the position, keeping it open for the reads that follow.
+struct ChunkedBlobReader {
+    // The offset and path of each chunk.
+    chunks: Vec<(u64, PathBuf)>,
+    size: u64,
+    position: u64,
+    current: Option<(usize, File)>,
+}
+
+impl Read for ChunkedBlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let i = self
+            .chunks
+            .partition_point(|(offset, _)| *offset <= self.position)
+            - 1;
+        if !matches!(&self.current, Some((current, _)) if *current == i) {
+            self.current = Some((i, File::open(&self.chunks[i].1)?));
+        }
+        let (offset, path) = &self.chunks[i];
+        let end = self.chunks.get(i + 1).map_or(self.size, |(next, _)| *next);
+        let len = buf.len().min((end - self.position) as usize);
+        let file = &self.current.as_ref().unwrap().1;
+        let n = file.read_at(&mut buf[..len], self.position - offset)?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("chunk '{}' is truncated", path.display()),
+            ));
+        }
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for ChunkedBlobReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = seek_position(pos, self.position, self.size)?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::iter::FromIterator;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::Sha256Implementation;
+
+    // xorshift64, for content that does not compress or repeat.
+    fn noise(len: usize, mut state: u64) -> Vec<u8> {
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                state as u8
+            })
+            .collect()
+    }
+
+    fn chunk_sizes(content: &[u8]) -> Vec<usize> {
+        let mut src = content;
+        let mut chunker = Chunker::new(&mut src);
+        let mut sizes = vec![];
+        while let Some(chunk) = chunker.next_chunk().unwrap() {
+            sizes.push(chunk.len());
+        }
+        sizes
+    }
+
+    #[test]
+    fn boundaries() {
+        let content = noise(16 << 20, 1);
+        let sizes = chunk_sizes(&content);
+        assert_eq!(sizes.iter().sum::<usize>(), content.len());
+        assert!(sizes
+            .iter()
+            .all(|size| (MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(size)));
+
+        // Bytes inserted near the start shift everything after them, but only the chunk that
+        // holds them changes.
+        let mut edited = content[..1000].to_vec();
+        edited.extend_from_slice(b"inserted");
+        edited.extend_from_slice(&content[1000..]);
+        let edited_sizes = chunk_sizes(&edited);
+        assert_eq!(edited_sizes[0], sizes[0] + 8);
+        assert_eq!(edited_sizes[1..], sizes[1..]);
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = std::env::temp_dir().join(format!("keep-chunked-{:016x}", rand::random::<u64>()));
+        fs::create_dir_all(dir.join("blobs")).unwrap();
+        fs::create_dir_all(dir.join("partial")).unwrap();
+        let substance = ChunkedSubstance::new(&dir);
+        let later = SystemTime::now() + Duration::from_secs(60);
+        assert!(substance
+            .garbage(&BTreeSet::new(), later)
+            .unwrap()
+            .is_empty());
+        assert!(!dir.join("chunks").exists());
+        let content = noise(6 << 20, 2);
+        let shadow = substance.store_from_reader(&mut &content[..]).unwrap();
+        let mut edited = content.clone();
+        edited[3 << 20] ^= 1;
+        let edited_shadow = substance.store_from_reader(&mut &edited[..]).unwrap();
+        let chunk_count = fs::read_dir(dir.join("chunks/blobs"))
+            .unwrap()
+            .map(|entry| fs::read_dir(entry.unwrap().path()).unwrap().count())
+            .sum::<usize>();
+        assert_eq!(chunk_count, chunk_sizes(&content).len() + 1);
+
+        // Dropping the edited blob leaves its index and the chunk it does not share.
+        let reachable = BTreeSet::from_iter([shadow.content_hash().clone()]);
+        let garbage = substance.garbage(&reachable, later).unwrap();
+        assert_eq!(garbage.len(), 2);
+        assert!(garbage.contains(&substance.index_path(edited_shadow.content_hash())));
+        let earlier = SystemTime::now() - Duration::from_secs(60);
+        assert!(substance.garbage(&reachable, earlier).unwrap().is_empty());
+
+        for (shadow, content) in &[(shadow, &content), (edited_shadow, &edited)] {
+            let blob = shadow.content_hash();
+            substance
+                .check_blob(blob, Sha256Implementation::Read)
+                .unwrap();
+            let mut reader = substance.open_blob(blob).unwrap();
+            let offset = content.len() / 3;
+            reader.seek(SeekFrom::Start(offset as u64)).unwrap();
+            let mut read = vec![];
+            reader.read_to_end(&mut read).unwrap();
+            assert_eq!(read, &content[offset..]);
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,456 @@
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{self, Read, Seek, SeekFrom, Take, Write};
+use std::os::unix::fs::{FileExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{bail, Result};
+use zstd::stream::read::Decoder;
+use zstd::stream::write::Encoder;
+
+use super::{
+    copy_and_hash, is_unreachable, seek_position, BlobReader, CopyMethod, DigestMismatch,
+    FilesystemSubstance, Substance,
+};
+use crate::{ContentSha256, Shadow};
+
+// Keeps blobs compressed with zstd, in the layout of `FilesystemSubstance` with a ".zst" suffix.
+// Blobs that would not shrink, such as media and archives, are kept as plain files, so that a
+// directory of plain blobs can be switched to compression without being rewritten.
+//
+// A compressed blob is in the zstd seekable format: independent frames of `FRAME_SIZE` bytes of
+// content each, followed by a table of their sizes in a skippable frame, so that it can be read
+// from anywhere through a mount by decompressing a single frame. zstd(1) still decompresses it
+// as a whole.
+pub struct ZstdSubstance {
+    plain: FilesystemSubstance,
+    level: i32,
+}
+
+impl ZstdSubstance {
+    // Whether a blob is worth compressing is judged by compressing its start.
+    const SAMPLE_SIZE: usize = 1 << 20;
+
+    pub const DEFAULT_LEVEL: i32 = 3;
+
+    pub fn new(path: impl AsRef<Path>, level: i32) -> Self {
+        Self {
+            plain: FilesystemSubstance::new(path),
+            level,
+        }
+    }
+
+    fn compressed_path(&self, blob: &ContentSha256) -> PathBuf {
+        let mut path = self.plain.blob_file_path(blob).into_os_string();
+        path.push(".zst");
+        path.into()
+    }
+
+    // Saving less than a tenth is not worth decompressing on every read.
+    fn pays(&self, sample: &[u8]) -> Result<bool> {
+        if sample.is_empty() {
+            return Ok(false);
+        }
+        let compressed = zstd::block::compress(sample, self.level)?;
+        Ok(compressed.len() < sample.len() - sample.len() / 10)
+    }
+
+    // Writes the content to a new partial file, compressed or not, and hashes it.
+    fn write_partial(
+        &self,
+        partial_path: &Path,
+        src: &mut dyn Read,
+        compress: bool,
+    ) -> Result<Shadow> {
+        let mut partial_file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(partial_path)?;
+        let result = (|| {
+            let shadow = if compress {
+                let mut encoder = SeekableEncoder {
+                    dst: &mut partial_file,
+                    level: self.level,
+                    buf: Vec::with_capacity(FRAME_SIZE),
+                    frames: vec![],
+                };
+                let shadow = copy_and_hash(src, &mut encoder)?;
+                encoder.finish()?;
+                shadow
+            } else {
+                copy_and_hash(src, &mut partial_file)?
+            };
+            partial_file.set_permissions(Permissions::from_mode(0o444))?;
+            Ok(shadow)
+        })();
+        if result.is_err() {
+            fs::remove_file(partial_path)?;
+        }
+        result
+    }
+
+    fn rename_into_place(
+        &self,
+        partial_path: &Path,
+        blob: &ContentSha256,
+        compress: bool,
+    ) -> Result<()> {
+        let blob_parent = self.plain.blob_parent(blob);
+        if !blob_parent.exists() {
+            fs::create_dir(blob_parent)?;
+        }
+        let blob_path = if compress {
+            self.compressed_path(blob)
+        } else {
+            self.plain.blob_file_path(blob)
+        };
+        fs::rename(partial_path, blob_path)?;
+        Ok(())
+    }
+}
+
+impl Substance for ZstdSubstance {
+    fn store(&self, blob: &ContentSha256, src: &Path) -> Result<Option<CopyMethod>> {
+        if self.have_blob(blob) {
+            return Ok(None);
+        }
+
+        let mut source_file = File::open(src)?;
+        let sample = read_sample(&mut source_file)?;
+        if !self.pays(&sample)? {
+            return self.plain.store(blob, src);
+        }
+
+        let partial_parent = self.plain.partial_parent(blob);
+        if !partial_parent.exists() {
+            fs::create_dir(&partial_parent)?;
+        }
+        let partial_path = self.plain.partial_path(blob);
+        let shadow = self.write_partial(
+            &partial_path,
+            &mut sample.as_slice().chain(source_file),
+            true,
+        )?;
+        log::debug!("copied {} by {}", src.display(), CopyMethod::Zstd);
+        if shadow.content_hash() != blob {
+            fs::remove_file(&partial_path)?;
+            bail!(DigestMismatch {
+                path: src.to_path_buf(),
+                expected: blob.clone(),
+                found: shadow.content_hash().clone(),
+            });
+        }
+        self.rename_into_place(&partial_path, blob, true)?;
+        Ok(Some(CopyMethod::Zstd))
+    }
+
+    fn store_from_reader(&self, src: &mut dyn Read) -> Result<Shadow> {
+        let sample = read_sample(src)?;
+        let compress = self.pays(&sample)?;
+        let partial_path = self
+            .plain
+            .partial_dir()
+            .join(format!("reader-{:016x}", rand::random::<u64>()));
+        let shadow =
+            self.write_partial(&partial_path, &mut sample.as_slice().chain(src), compress)?;
+        if self.have_blob(shadow.content_hash()) {
+            fs::remove_file(&partial_path)?;
+        } else if let Err(err) =
+            self.rename_into_place(&partial_path, shadow.content_hash(), compress)
+        {
+            fs::remove_file(&partial_path)?;
+            return Err(err);
+        }
+        Ok(shadow)
+    }
+
+    fn have_blob(&self, blob: &ContentSha256) -> bool {
+        self.compressed_path(blob).is_file() || self.plain.have_blob(blob)
+    }
+
+    fn open_blob(&self, blob: &ContentSha256) -> Result<Box<dyn BlobReader>> {
+        let compressed_path = self.compressed_path(blob);
+        if !compressed_path.is_file() {
+            return self.plain.open_blob(blob);
+        }
+        Ok(Box::new(ZstdBlobReader::new(compressed_path)?))
+    }
+
+    // Only blobs that were not worth compressing have one.
+    fn blob_path(&self, blob: &ContentSha256) -> Option<PathBuf> {
+        self.plain.blob_path(blob).filter(|path| path.is_file())
+    }
+
+    fn garbage(
+        &self,
+        reachable: &BTreeSet<ContentSha256>,
+        cutoff: SystemTime,
+    ) -> Result<Vec<PathBuf>> {
+        self.plain.blob_garbage(cutoff, |name| {
+            is_unreachable(name.strip_suffix(".zst").unwrap_or(name), reachable)
+        })
+    }
+}
+
+fn read_sample(src: &mut dyn Read) -> Result<Vec<u8>> {
+    let mut sample = vec![];
+    src.take(ZstdSubstance::SAMPLE_SIZE as u64)
+        .read_to_end(&mut sample)?;
+    Ok(sample)
+}
+
+const FRAME_SIZE: usize = 1 << 20;
+const SKIPPABLE_MAGIC: u32 = 0x184d2a5e;
+const SEEKABLE_MAGIC: u32 = 0x8f92eab1;
+const FOOTER_SIZE: u64 = 9;
+
+struct SeekableEncoder<W> {
+    dst: W,
+    level: i32,
+    buf: Vec<u8>,
+    // The compressed and decompressed size of each frame written.
+    frames: Vec<(u32, u32)>,
+}
+
+impl<W: Write> SeekableEncoder<W> {
+    fn write_frame(&mut self) -> io::Result<()> {
+        let mut encoder = Encoder::new(vec![], self.level)?;
+        encoder.include_checksum(true)?;
+        encoder.write_all(&self.buf)?;
+        let frame = encoder.finish()?;
+        self.dst.write_all(&frame)?;
+        self.frames
+            .push((frame.len() as u32, self.buf.len() as u32));
+        self.buf.clear();
+        Ok(())
+    }
+
+    // Writes the last frame and the seek table, without frame checksums, as each frame has its
+    // own.
+    fn finish(mut self) -> io::Result<()> {
+        if !self.buf.is_empty() {
+            self.write_frame()?;
+        }
+        let mut table = vec![];
+        for (compressed_size, size) in &self.frames {
+            table.extend_from_slice(&compressed_size.to_le_bytes());
+            table.extend_from_slice(&size.to_le_bytes());
+        }
+        table.extend_from_slice(&(self.frames.len() as u32).to_le_bytes());
+        table.push(0);
+        table.extend_from_slice(&SEEKABLE_MAGIC.to_le_bytes());
+        self.dst.write_all(&SKIPPABLE_MAGIC.to_le_bytes())?;
+        self.dst.write_all(&(table.len() as u32).to_le_bytes())?;
+        self.dst.write_all(&table)?;
+        self.dst.flush()
+    }
+}
+
+impl<W: Write> Write for SeekableEncoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = buf.len().min(FRAME_SIZE - self.buf.len());
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() == FRAME_SIZE {
+            self.write_frame()?;
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct Frame {
+    offset: u64,
+    compressed_size: u64,
+    // Of its content within the blob.
+    start: u64,
+}
+
+type FrameDecoder = Decoder<'static, io::BufReader<Take<File>>>;
+
+// Reads in order decompress each frame once. Seeking within the frame being read decompresses
+// and discards up to the new position, or starts the frame again if the position is behind.
+struct ZstdBlobReader {
+    path: PathBuf,
+    file: File,
+    frames: Vec<Frame>,
+    size: u64,
+    position: u64,
+    // The index of the frame being read, and how far into the blob it has been read.
+    current: Option<(usize, FrameDecoder, u64)>,
+}
+
+impl ZstdBlobReader {
+    fn new(path: PathBuf) -> io::Result<Self> {
+        let file = File::open(&path)?;
+        let (frames, size) = read_seek_table(&file).map_err(|err| match err.kind() {
+            io::ErrorKind::InvalidData => {
+                io::Error::new(err.kind(), format!("'{}': {}", path.display(), err))
+            }
+            _ => err,
+        })?;
+        Ok(Self {
+            path,
+            file,
+            frames,
+            size,
+            position: 0,
+            current: None,
+        })
+    }
+}
+
+// The frames listed in the seek table, and the size of the content.
+fn read_seek_table(file: &File) -> io::Result<(Vec<Frame>, u64)> {
+    let corrupt = |what| io::Error::new(io::ErrorKind::InvalidData, what);
+    let len = file.metadata()?.len();
+    if len < FOOTER_SIZE + 8 {
+        return Err(corrupt("missing zstd seek table"));
+    }
+    let mut footer = [0; FOOTER_SIZE as usize];
+    file.read_exact_at(&mut footer, len - FOOTER_SIZE)?;
+    if footer[5..] != SEEKABLE_MAGIC.to_le_bytes() {
+        return Err(corrupt("missing zstd seek table"));
+    }
+    let count = u64::from(u32::from_le_bytes(footer[..4].try_into().unwrap()));
+    // Frame checksums may be listed along with the sizes.
+    let entry_size = if footer[4] & 0x80 != 0 { 12 } else { 8 };
+    let table_size = count * entry_size + FOOTER_SIZE;
+    let data_size = len
+        .checked_sub(table_size + 8)
+        .ok_or_else(|| corrupt("corrupt zstd seek table"))?;
+    let mut table = vec![0; (table_size + 8) as usize];
+    file.read_exact_at(&mut table, data_size)?;
+    let field = |offset: usize| u32::from_le_bytes(table[offset..offset + 4].try_into().unwrap());
+    if field(0) != SKIPPABLE_MAGIC || u64::from(field(4)) != table_size {
+        return Err(corrupt("corrupt zstd seek table"));
+    }
+    let mut frames = vec![];
+    let (mut offset, mut start) = (0, 0);
+    for i in 0..count as usize {
+        let entry = 8 + i * entry_size as usize;
+        let compressed_size = u64::from(field(entry));
+        frames.push(Frame {
+            offset,
+            compressed_size,
+            start,
+        });
+        offset += compressed_size;
+        start += u64::from(field(entry + 4));
+    }
+    if offset != data_size {
+        return Err(corrupt("corrupt zstd seek table"));
+    }
+    Ok((frames, start))
+}
+
+impl Read for ZstdBlobReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.position >= self.size {
+            return Ok(0);
+        }
+        let i = self
+            .frames
+            .partition_point(|frame| frame.start <= self.position)
+            - 1;
+        if !matches!(&self.current, Some((j, _, reached)) if *j == i && *reached <= self.position) {
+            let frame = &self.frames[i];
+            let mut file = self.file.try_clone()?;
+            file.seek(SeekFrom::Start(frame.offset))?;
+            let decoder = Decoder::new(file.take(frame.compressed_size))?;
+            self.current = Some((i, decoder, frame.start));
+        }
+        // A frame is read no further than where the next one starts.
+        let end = self
+            .frames
+            .get(i + 1)
+            .map_or(self.size, |frame| frame.start);
+        let position = self.position;
+        let (_, decoder, reached) = self.current.as_mut().unwrap();
+        *reached += io::copy(
+            &mut decoder.by_ref().take(position - *reached),
+            &mut io::sink(),
+        )?;
+        let n = if *reached < position {
+            0
+        } else {
+            let len = (end - position).min(buf.len() as u64) as usize;
+            let n = decoder.read(&mut buf[..len])?;
+            *reached += n as u64;
+            n
+        };
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "'{}' is shorter than its seek table says",
+                    self.path.display()
+                ),
+            ));
+        }
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for ZstdBlobReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        // Past the end, reads return nothing.
+        self.position = seek_position(pos, self.position, self.size)?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn seek() {
+        // Over two frames, so seeks cross frame boundaries in both directions.
+        let content = (0..700_000u32)
+            .flat_map(|i| (i % 1000).to_le_bytes())
+            .collect::<Vec<u8>>();
+        let path = std::env::temp_dir().join(format!("keep-zstd-{:016x}", rand::random::<u64>()));
+        let mut file = File::create(&path).unwrap();
+        let mut encoder = SeekableEncoder {
+            dst: &mut file,
+            level: 3,
+            buf: Vec::with_capacity(FRAME_SIZE),
+            frames: vec![],
+        };
+        encoder.write_all(&content).unwrap();
+        encoder.finish().unwrap();
+        drop(file);
+        assert_eq!(
+            zstd::decode_all(File::open(&path).unwrap()).unwrap(),
+            content
+        );
+
+        let mut reader = ZstdBlobReader::new(path.clone()).unwrap();
+        assert_eq!(reader.frames.len(), 3);
+        let mut buf = [0; 16];
+        for &offset in &[1000, 2_500_000, 20, 20, 1_048_570, 2_799_990, 1_048_576] {
+            reader.seek(SeekFrom::Start(offset)).unwrap();
+            let n = reader.read(&mut buf).unwrap();
+            let offset = offset as usize;
+            assert!(n > 0);
+            assert_eq!(&buf[..n], &content[offset..offset + n]);
+        }
+        assert_eq!(reader.seek(SeekFrom::End(-4)).unwrap(), 2_799_996);
+        let mut tail = vec![];
+        reader.read_to_end(&mut tail).unwrap();
+        assert_eq!(tail, &content[2_799_996..]);
+        reader.seek(SeekFrom::Start(3_000_000)).unwrap();
+        assert_eq!(reader.read(&mut buf).unwrap(), 0);
+
+        // Blobs must have a seek table.
+        fs::write(&path, zstd::encode_all(&content[..], 3).unwrap()).unwrap();
+        assert!(ZstdBlobReader::new(path.clone()).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
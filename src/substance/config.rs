@@ -0,0 +1,179 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use anyhow::{bail, Context, Result};
+
+use super::{ChunkedSubstance, EncryptedSubstance, FilesystemSubstance, Substance, ZstdSubstance};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    None,
+    Zstd { level: i32 },
+}
+
+// The `config` file of a substance directory, of `name = value` lines. The backend must stay the
+// same for the life of the directory, so it is recorded there rather than given on each command.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SubstanceConfig {
+    pub compression: Compression,
+    // A key file kept off the drive that holds the substance directory.
+    pub encryption_key: Option<PathBuf>,
+    // Whether blobs are split into content-defined chunks, kept once each.
+    pub chunking: bool,
+}
+
+impl Default for SubstanceConfig {
+    fn default() -> Self {
+        Self {
+            compression: Compression::None,
+            encryption_key: None,
+            chunking: false,
+        }
+    }
+}
+
+impl SubstanceConfig {
+    const FILE_NAME: &'static str = "config";
+
+    // A directory without a config holds plain blobs. A relative key file is taken to be
+    // relative to the substance directory, wherever keep is run from.
+    pub fn read(substance_dir: &Path) -> Result<Self> {
+        let path = substance_dir.join(Self::FILE_NAME);
+        let mut config = match fs::read_to_string(&path) {
+            Ok(s) => Self::parse(&s).with_context(|| format!("in '{}'", path.display()))?,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Self::default(),
+            Err(err) => return Err(err.into()),
+        };
+        if let Some(key_file) = &mut config.encryption_key {
+            *key_file = substance_dir.join(&key_file);
+        }
+        Ok(config)
+    }
+
+    fn parse(s: &str) -> Result<Self> {
+        let mut config = Self::default();
+        let mut level = None;
+        for (i, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = match line.split_once('=') {
+                Some((name, value)) => (name.trim(), value.trim()),
+                None => bail!("line {}: expected 'name = value'", i + 1),
+            };
+            match name {
+                "compression" => {
+                    config.compression = match value {
+                        "none" => Compression::None,
+                        "zstd" => Compression::Zstd {
+                            level: ZstdSubstance::DEFAULT_LEVEL,
+                        },
+                        _ => bail!("line {}: unknown compression '{}'", i + 1, value),
+                    }
+                }
+                "compression-level" => {
+                    level = Some(
+                        value
+                            .parse()
+                            .with_context(|| format!("line {}: compression level", i + 1))?,
+                    )
+                }
+                "encryption-key" => config.encryption_key = Some(PathBuf::from(value)),
+                "chunking" => {
+                    config.chunking = match value {
+                        "none" => false,
+                        "gear" => true,
+                        _ => bail!("line {}: unknown chunking '{}'", i + 1, value),
+                    }
+                }
+                _ => bail!("line {}: unknown setting '{}'", i + 1, name),
+            }
+        }
+        if let Some(level) = level {
+            match &mut config.compression {
+                Compression::Zstd { level: l } => *l = level,
+                Compression::None => bail!("compression level without compression"),
+            }
+        }
+        Ok(config)
+    }
+}
+
+pub fn open_substance(substance_dir: &Path) -> Result<Box<dyn Substance>> {
+    let config = SubstanceConfig::read(substance_dir)?;
+    Ok(
+        match (config.compression, config.encryption_key, config.chunking) {
+            (Compression::None, None, false) => Box::new(FilesystemSubstance::new(substance_dir)),
+            (Compression::Zstd { level }, None, false) => {
+                Box::new(ZstdSubstance::new(substance_dir, level))
+            }
+            (Compression::None, Some(key_file), false) => {
+                Box::new(EncryptedSubstance::new(substance_dir, &key_file)?)
+            }
+            (Compression::None, None, true) => Box::new(ChunkedSubstance::new(substance_dir)),
+            _ => bail!("compression, encryption and chunking cannot be combined"),
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(
+            SubstanceConfig::parse("").unwrap(),
+            SubstanceConfig::default()
+        );
+        assert_eq!(
+            SubstanceConfig::parse("# external drive\ncompression = zstd\ncompression-level=19\n")
+                .unwrap()
+                .compression,
+            Compression::Zstd { level: 19 }
+        );
+        assert!(SubstanceConfig::parse("compression = lz4").is_err());
+        assert!(SubstanceConfig::parse("compression-level = 19").is_err());
+        assert!(SubstanceConfig::parse("compression").is_err());
+        assert!(SubstanceConfig::parse("chunking = gear").unwrap().chunking);
+        assert_eq!(
+            SubstanceConfig::parse("encryption-key = /media/key")
+                .unwrap()
+                .encryption_key,
+            Some(PathBuf::from("/media/key"))
+        );
+    }
+
+    #[test]
+    fn read() {
+        let dir = std::env::temp_dir().join(format!("keep-config-{:016x}", rand::random::<u64>()));
+        fs::create_dir(&dir).unwrap();
+        assert_eq!(
+            SubstanceConfig::read(&dir).unwrap(),
+            SubstanceConfig::default()
+        );
+        fs::write(
+            dir.join("config"),
+            "encryption-key = ../key
+",
+        )
+        .unwrap();
+        assert_eq!(
+            SubstanceConfig::read(&dir).unwrap().encryption_key,
+            Some(dir.join("../key"))
+        );
+        fs::write(
+            dir.join("config"),
+            "encryption-key = /media/key
+",
+        )
+        .unwrap();
+        assert_eq!(
+            SubstanceConfig::read(&dir).unwrap().encryption_key,
+            Some(PathBuf::from("/media/key"))
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,414 @@
+use std::collections::BTreeSet;
+use std::convert::TryInto;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{FileExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use anyhow::{anyhow, bail, Context, Result};
+use chacha20poly1305::aead::{Aead, NewAead, Payload};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use hmac::{Hmac, Mac, NewMac};
+use sha2::Sha256;
+
+use super::{
+    copy_and_hash, is_unreachable, seek_position, BlobReader, CopyMethod, DigestMismatch,
+    FilesystemSubstance, Substance,
+};
+use crate::{ContentSha256, Shadow};
+
+// Keeps blobs encrypted, for a substance directory on a drive that is not trusted. Blobs are
+// named by a keyed MAC of their digest rather than by the digest, which would tell which known
+// files are kept, and are otherwise in the layout of `FilesystemSubstance`.
+//
+// A blob file is a header followed by the content in segments, each sealed with
+// XChaCha20-Poly1305, so that it can be read from anywhere through a mount. The header holds the
+// random nonce prefix of the file and the sealed digest of the content, which ties the file to
+// its name. The last segment is marked as such, so that truncation is detected.
+pub struct EncryptedSubstance {
+    plain: FilesystemSubstance,
+    cipher_key: [u8; KEY_SIZE],
+    name_key: [u8; KEY_SIZE],
+}
+
+const KEY_SIZE: usize = 32;
+const MAGIC: &[u8; 8] = b"keepenc1";
+const PREFIX_SIZE: usize = 16;
+const TAG_SIZE: usize = 16;
+const HEADER_SIZE: u64 = (MAGIC.len() + PREFIX_SIZE + KEY_SIZE + TAG_SIZE) as u64;
+const SEGMENT_SIZE: u64 = 1 << 16;
+
+impl EncryptedSubstance {
+    // The key file holds 32 random bytes, such as from /dev/urandom, and belongs elsewhere than
+    // the drive. The keys for contents and for names are derived from it.
+    pub fn new(path: impl AsRef<Path>, key_file: &Path) -> Result<Self> {
+        let key = fs::read(key_file)
+            .with_context(|| format!("reading key file '{}'", key_file.display()))?;
+        if key.len() != KEY_SIZE {
+            bail!(
+                "key file '{}' must hold {} bytes",
+                key_file.display(),
+                KEY_SIZE
+            );
+        }
+        Ok(Self {
+            plain: FilesystemSubstance::new(path),
+            cipher_key: hmac_sha256(&key, b"keep blob contents"),
+            name_key: hmac_sha256(&key, b"keep blob names"),
+        })
+    }
+
+    // The MAC has the size of a digest, and is laid out as one.
+    fn name(&self, blob: &ContentSha256) -> ContentSha256 {
+        ContentSha256::new(hmac_sha256(&self.name_key, blob.as_bytes()))
+    }
+
+    fn encrypted_path(&self, blob: &ContentSha256) -> PathBuf {
+        self.plain.blob_file_path(&self.name(blob))
+    }
+
+    fn cipher(&self) -> XChaCha20Poly1305 {
+        XChaCha20Poly1305::new(Key::from_slice(&self.cipher_key))
+    }
+
+    // The header is written last, once the digest is known.
+    fn write_partial(&self, partial_path: &Path, src: &mut dyn Read) -> Result<Shadow> {
+        let partial_file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(partial_path)?;
+        let result = (|| {
+            let prefix = rand::random::<[u8; PREFIX_SIZE]>();
+            let cipher = self.cipher();
+            (&partial_file).write_all(&[0; HEADER_SIZE as usize])?;
+            let mut writer = SegmentWriter {
+                dst: &partial_file,
+                cipher: &cipher,
+                prefix,
+                index: 0,
+                buf: Vec::with_capacity(SEGMENT_SIZE as usize),
+            };
+            let shadow = copy_and_hash(src, &mut writer)?;
+            writer.finish()?;
+            let sealed = cipher
+                .encrypt(
+                    &nonce(&prefix, u64::MAX),
+                    Payload {
+                        msg: shadow.content_hash().as_bytes(),
+                        aad: MAGIC,
+                    },
+                )
+                .map_err(|_| anyhow!("encryption failed"))?;
+            let mut header = MAGIC.to_vec();
+            header.extend_from_slice(&prefix);
+            header.extend_from_slice(&sealed);
+            partial_file.write_all_at(&header, 0)?;
+            partial_file.set_permissions(Permissions::from_mode(0o444))?;
+            Ok(shadow)
+        })();
+        if result.is_err() {
+            fs::remove_file(partial_path)?;
+        }
+        result
+    }
+
+    fn rename_into_place(&self, partial_path: &Path, name: &ContentSha256) -> Result<()> {
+        let blob_parent = self.plain.blob_parent(name);
+        if !blob_parent.exists() {
+            fs::create_dir(blob_parent)?;
+        }
+        fs::rename(partial_path, self.plain.blob_file_path(name))?;
+        Ok(())
+    }
+
+    fn open_segments(&self, blob: &ContentSha256) -> Result<SegmentReader> {
+        let path = self.encrypted_path(blob);
+        let file = File::open(&path)?;
+        let len = file.metadata()?.len();
+        if len < HEADER_SIZE + TAG_SIZE as u64 {
+            bail!("'{}' is truncated", path.display());
+        }
+        let mut header = [0; HEADER_SIZE as usize];
+        file.read_exact_at(&mut header, 0)?;
+        if &header[..MAGIC.len()] != MAGIC {
+            bail!("'{}' is not an encrypted blob", path.display());
+        }
+        let prefix = header[MAGIC.len()..MAGIC.len() + PREFIX_SIZE]
+            .try_into()
+            .unwrap();
+        let cipher = self.cipher();
+        let digest = cipher
+            .decrypt(
+                &nonce(&prefix, u64::MAX),
+                Payload {
+                    msg: &header[MAGIC.len() + PREFIX_SIZE..],
+                    aad: MAGIC,
+                },
+            )
+            .map_err(|_| anyhow!("'{}' is corrupt or under another key", path.display()))?;
+        if digest != blob.as_bytes() {
+            bail!("'{}' does not hold blob {}", path.display(), blob);
+        }
+        let segments = (len - HEADER_SIZE + SEGMENT_SIZE + TAG_SIZE as u64 - 1)
+            / (SEGMENT_SIZE + TAG_SIZE as u64);
+        Ok(SegmentReader {
+            file,
+            path,
+            cipher,
+            prefix,
+            len,
+            segments,
+            size: len - HEADER_SIZE - segments * TAG_SIZE as u64,
+            position: 0,
+            segment: None,
+        })
+    }
+}
+
+impl Substance for EncryptedSubstance {
+    fn store(&self, blob: &ContentSha256, src: &Path) -> Result<Option<CopyMethod>> {
+        if self.have_blob(blob) {
+            return Ok(None);
+        }
+
+        let name = self.name(blob);
+        let partial_parent = self.plain.partial_parent(&name);
+        if !partial_parent.exists() {
+            fs::create_dir(&partial_parent)?;
+        }
+        let partial_path = self.plain.partial_path(&name);
+        let shadow = self.write_partial(&partial_path, &mut File::open(src)?)?;
+        log::debug!("copied {} by {}", src.display(), CopyMethod::Encrypt);
+        if shadow.content_hash() != blob {
+            fs::remove_file(&partial_path)?;
+            bail!(DigestMismatch {
+                path: src.to_path_buf(),
+                expected: blob.clone(),
+                found: shadow.content_hash().clone(),
+            });
+        }
+        self.rename_into_place(&partial_path, &name)?;
+        Ok(Some(CopyMethod::Encrypt))
+    }
+
+    fn store_from_reader(&self, src: &mut dyn Read) -> Result<Shadow> {
+        let partial_path = self
+            .plain
+            .partial_dir()
+            .join(format!("reader-{:016x}", rand::random::<u64>()));
+        let shadow = self.write_partial(&partial_path, src)?;
+        if self.have_blob(shadow.content_hash()) {
+            fs::remove_file(&partial_path)?;
+        } else if let Err(err) =
+            self.rename_into_place(&partial_path, &self.name(shadow.content_hash()))
+        {
+            fs::remove_file(&partial_path)?;
+            return Err(err);
+        }
+        Ok(shadow)
+    }
+
+    fn have_blob(&self, blob: &ContentSha256) -> bool {
+        self.encrypted_path(blob).is_file()
+    }
+
+    fn open_blob(&self, blob: &ContentSha256) -> Result<Box<dyn BlobReader>> {
+        Ok(Box::new(self.open_segments(blob)?))
+    }
+
+    fn garbage(
+        &self,
+        reachable: &BTreeSet<ContentSha256>,
+        cutoff: SystemTime,
+    ) -> Result<Vec<PathBuf>> {
+        let names = reachable.iter().map(|blob| self.name(blob)).collect();
+        self.plain
+            .blob_garbage(cutoff, |name| is_unreachable(name, &names))
+    }
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> [u8; KEY_SIZE] {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac.finalize().into_bytes().into()
+}
+
+// Segments are numbered from 0. The header takes the last number.
+fn nonce(prefix: &[u8; PREFIX_SIZE], index: u64) -> XNonce {
+    let mut nonce = [0; PREFIX_SIZE + 8];
+    nonce[..PREFIX_SIZE].copy_from_slice(prefix);
+    nonce[PREFIX_SIZE..].copy_from_slice(&index.to_be_bytes());
+    XNonce::clone_from_slice(&nonce)
+}
+
+// Whether a segment is the last is authenticated along with it.
+fn segment_aad(last: bool) -> [u8; 1] {
+    [last as u8]
+}
+
+// A full segment is only sealed once more content follows it, as the last must be marked. The
+// last segment is empty only if the whole content is.
+struct SegmentWriter<'a> {
+    dst: &'a File,
+    cipher: &'a XChaCha20Poly1305,
+    prefix: [u8; PREFIX_SIZE],
+    index: u64,
+    buf: Vec<u8>,
+}
+
+impl SegmentWriter<'_> {
+    fn seal(&mut self, last: bool) -> io::Result<()> {
+        let sealed = self
+            .cipher
+            .encrypt(
+                &nonce(&self.prefix, self.index),
+                Payload {
+                    msg: &self.buf,
+                    aad: &segment_aad(last),
+                },
+            )
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "encryption failed"))?;
+        self.dst.write_all(&sealed)?;
+        self.buf.clear();
+        self.index += 1;
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.seal(true)
+    }
+}
+
+impl Write for SegmentWriter<'_> {
+    fn write(&mut self, mut buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len();
+        while !buf.is_empty() {
+            if self.buf.len() == SEGMENT_SIZE as usize {
+                self.seal(false)?;
+            }
+            let n = buf.len().min(SEGMENT_SIZE as usize - self.buf.len());
+            self.buf.extend_from_slice(&buf[..n]);
+            buf = &buf[n..];
+        }
+        Ok(len)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// Decrypts the segment that holds the position, and keeps it for the reads that follow.
+struct SegmentReader {
+    file: File,
+    path: PathBuf,
+    cipher: XChaCha20Poly1305,
+    prefix: [u8; PREFIX_SIZE],
+    len: u64,
+    segments: u64,
+    size: u64,
+    position: u64,
+    segment: Option<(u64, Vec<u8>)>,
+}
+
+impl SegmentReader {
+    fn load(&mut self, index: u64) -> io::Result<&[u8]> {
+        if !matches!(&self.segment, Some((i, _)) if *i == index) {
+            let sealed_size = SEGMENT_SIZE + TAG_SIZE as u64;
+            let offset = HEADER_SIZE + index * sealed_size;
+            let mut sealed = vec![0; sealed_size.min(self.len - offset) as usize];
+            self.file.read_exact_at(&mut sealed, offset)?;
+            let plain = self
+                .cipher
+                .decrypt(
+                    &nonce(&self.prefix, index),
+                    Payload {
+                        msg: &sealed,
+                        aad: &segment_aad(index == self.segments - 1),
+                    },
+                )
+                .map_err(|_| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        format!("segment {} of '{}' is corrupt", index, self.path.display()),
+                    )
+                })?;
+            self.segment = Some((index, plain));
+        }
+        Ok(&self.segment.as_ref().unwrap().1)
+    }
+}
+
+impl Read for SegmentReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+        let index = self.position / SEGMENT_SIZE;
+        let start = (self.position % SEGMENT_SIZE) as usize;
+        let segment = self.load(index)?;
+        let n = buf.len().min(segment.len() - start);
+        buf[..n].copy_from_slice(&segment[start..start + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for SegmentReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = seek_position(pos, self.position, self.size)?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Sha256Implementation;
+
+    #[test]
+    fn round_trip() {
+        let dir =
+            std::env::temp_dir().join(format!("keep-encrypted-{:016x}", rand::random::<u64>()));
+        fs::create_dir_all(dir.join("blobs")).unwrap();
+        fs::create_dir_all(dir.join("partial")).unwrap();
+        fs::write(dir.join("key"), [7; KEY_SIZE]).unwrap();
+        let substance = EncryptedSubstance::new(&dir, &dir.join("key")).unwrap();
+        for &len in &[0, 1, SEGMENT_SIZE as usize, 3 * SEGMENT_SIZE as usize + 5] {
+            let content = (0..len).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+            let shadow = substance.store_from_reader(&mut &content[..]).unwrap();
+            let blob = shadow.content_hash();
+            assert!(!substance
+                .encrypted_path(blob)
+                .ends_with(&blob.to_hex()[3..]));
+            substance
+                .check_blob(blob, Sha256Implementation::Read)
+                .unwrap();
+
+            let mut reader = substance.open_blob(blob).unwrap();
+            assert_eq!(reader.seek(SeekFrom::End(0)).unwrap(), len as u64);
+            let offset = len / 2;
+            reader.seek(SeekFrom::Start(offset as u64)).unwrap();
+            let mut read = vec![];
+            reader.read_to_end(&mut read).unwrap();
+            assert_eq!(read, &content[offset..]);
+
+            // Truncated at a segment boundary.
+            let path = substance.encrypted_path(blob);
+            let sealed = fs::read(&path).unwrap();
+            if sealed.len() as u64 > HEADER_SIZE + SEGMENT_SIZE + TAG_SIZE as u64 {
+                fs::remove_file(&path).unwrap();
+                fs::write(
+                    &path,
+                    &sealed[..(HEADER_SIZE + SEGMENT_SIZE) as usize + TAG_SIZE],
+                )
+                .unwrap();
+                assert!(substance
+                    .check_blob(blob, Sha256Implementation::Read)
+                    .is_err());
+            }
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,639 @@
+use std::alloc::{self, Layout};
+use std::collections::BTreeSet;
+use std::fmt;
+use std::fs::{self, File, OpenOptions, Permissions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::{Deref, DerefMut};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output, Stdio};
+use std::ptr::{self, NonNull};
+use std::slice;
+use std::str::FromStr;
+use std::time::SystemTime;
+
+use anyhow::{anyhow, bail, Result};
+use lazy_static::lazy_static;
+use regex::bytes::Regex;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::{ContentSha256, Shadow};
+
+mod chunked;
+mod compressed;
+mod config;
+mod encrypted;
+
+pub use self::chunked::ChunkedSubstance;
+pub use self::compressed::ZstdSubstance;
+pub use self::config::{open_substance, Compression, SubstanceConfig};
+pub use self::encrypted::EncryptedSubstance;
+
+// Blobs are read back through `open_blob`, so that a backend need not keep them as plain files.
+pub trait Substance {
+    // Returns how the content was copied, or None if there was nothing to copy.
+    fn store(&self, blob: &ContentSha256, src: &Path) -> Result<Option<CopyMethod>>;
+
+    // For content that is not in a file, such as archive members. The digest is only known once
+    // the content has been read.
+    fn store_from_reader(&self, src: &mut dyn Read) -> Result<Shadow>;
+
+    fn have_blob(&self, blob: &ContentSha256) -> bool;
+
+    // The content of a blob, as read through a mount.
+    fn open_blob(&self, blob: &ContentSha256) -> Result<Box<dyn BlobReader>>;
+
+    // Where the blob is kept as a plain file, if it is. It can then be hashed by any
+    // implementation rather than only by reading it back.
+    fn blob_path(&self, _blob: &ContentSha256) -> Option<PathBuf> {
+        None
+    }
+
+    fn check_blob(&self, blob: &ContentSha256, implementation: Sha256Implementation) -> Result<()> {
+        match self.blob_path(blob) {
+            Some(path) => check_sha256sum(blob, &path, implementation),
+            None => check_sha256sum_reader(blob, &mut self.open_blob(blob)?, implementation),
+        }
+    }
+
+    // The files that keep nothing but blobs outside of `reachable`, for gc. Files modified since
+    // `cutoff` are spared, as their blobs may be being stored for a tree not yet committed.
+    fn garbage(
+        &self,
+        reachable: &BTreeSet<ContentSha256>,
+        cutoff: SystemTime,
+    ) -> Result<Vec<PathBuf>>;
+}
+
+pub trait BlobReader: Read + Seek {}
+
+impl<T: Read + Seek> BlobReader for T {}
+
+// Where `pos` leads, for readers that know the size of the content. As with files, seeking past
+// the end is allowed, and reads from there return nothing.
+fn seek_position(pos: SeekFrom, position: u64, size: u64) -> io::Result<u64> {
+    let (base, delta) = match pos {
+        SeekFrom::Start(offset) => (offset, 0),
+        SeekFrom::Current(delta) => (position, delta),
+        SeekFrom::End(delta) => (size, delta),
+    };
+    let target = if delta < 0 {
+        base.checked_sub(delta.unsigned_abs())
+    } else {
+        base.checked_add(delta as u64)
+    };
+    target.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid seek"))
+}
+
+// The backend of a substance directory is only known once its config has been read.
+impl Substance for Box<dyn Substance> {
+    fn store(&self, blob: &ContentSha256, src: &Path) -> Result<Option<CopyMethod>> {
+        (**self).store(blob, src)
+    }
+
+    fn store_from_reader(&self, src: &mut dyn Read) -> Result<Shadow> {
+        (**self).store_from_reader(src)
+    }
+
+    fn have_blob(&self, blob: &ContentSha256) -> bool {
+        (**self).have_blob(blob)
+    }
+
+    fn open_blob(&self, blob: &ContentSha256) -> Result<Box<dyn BlobReader>> {
+        (**self).open_blob(blob)
+    }
+
+    fn blob_path(&self, blob: &ContentSha256) -> Option<PathBuf> {
+        (**self).blob_path(blob)
+    }
+
+    fn check_blob(&self, blob: &ContentSha256, implementation: Sha256Implementation) -> Result<()> {
+        (**self).check_blob(blob, implementation)
+    }
+
+    fn garbage(
+        &self,
+        reachable: &BTreeSet<ContentSha256>,
+        cutoff: SystemTime,
+    ) -> Result<Vec<PathBuf>> {
+        (**self).garbage(reachable, cutoff)
+    }
+}
+
+pub struct FilesystemSubstance {
+    path: PathBuf,
+}
+
+impl FilesystemSubstance {
+    const SPLIT: usize = 3;
+
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    fn blob_dir(&self) -> PathBuf {
+        self.path.join("blobs")
+    }
+
+    fn partial_dir(&self) -> PathBuf {
+        self.path.join("partial")
+    }
+
+    fn blob_relative_path(blob: &ContentSha256) -> (String, String) {
+        let mut hex = blob.to_hex();
+        let child = hex.split_off(Self::SPLIT);
+        (hex, child)
+    }
+
+    fn blob_file_path(&self, blob: &ContentSha256) -> PathBuf {
+        let (parent, child) = Self::blob_relative_path(blob);
+        self.blob_dir().join(&parent).join(&child)
+    }
+
+    fn blob_parent(&self, blob: &ContentSha256) -> PathBuf {
+        let (parent, _child) = Self::blob_relative_path(blob);
+        self.blob_dir().join(&parent)
+    }
+
+    fn partial_path(&self, blob: &ContentSha256) -> PathBuf {
+        let (parent, child) = Self::blob_relative_path(blob);
+        self.partial_dir().join(&parent).join(&child)
+    }
+
+    fn partial_parent(&self, blob: &ContentSha256) -> PathBuf {
+        let (parent, _child) = Self::blob_relative_path(blob);
+        self.partial_dir().join(&parent)
+    }
+
+    // Every file in the blob directory, with its name, which is the digest of its blob in hex,
+    // or a name of the same form, along with any suffix.
+    fn blob_files(&self) -> Result<Vec<(String, PathBuf)>> {
+        let mut files = vec![];
+        for parent in fs::read_dir(self.blob_dir())? {
+            let parent = parent?;
+            if !parent.file_type()?.is_dir() {
+                continue;
+            }
+            for child in fs::read_dir(parent.path())? {
+                let child = child?;
+                let name = format!(
+                    "{}{}",
+                    parent.file_name().to_string_lossy(),
+                    child.file_name().to_string_lossy()
+                );
+                files.push((name, child.path()));
+            }
+        }
+        Ok(files)
+    }
+
+    // The blob files of which `is_garbage` takes the name to be garbage, modified before
+    // `cutoff`.
+    fn blob_garbage(
+        &self,
+        cutoff: SystemTime,
+        mut is_garbage: impl FnMut(&str) -> bool,
+    ) -> Result<Vec<PathBuf>> {
+        let mut garbage = vec![];
+        for (name, path) in self.blob_files()? {
+            if is_garbage(&name) && fs::symlink_metadata(&path)?.modified()? < cutoff {
+                garbage.push(path);
+            }
+        }
+        Ok(garbage)
+    }
+}
+
+// Names that are not of a digest are never taken to be garbage.
+fn is_unreachable(name: &str, reachable: &BTreeSet<ContentSha256>) -> bool {
+    matches!(ContentSha256::from_hex(name), Ok(blob) if !reachable.contains(&blob))
+}
+
+impl Substance for FilesystemSubstance {
+    fn store(&self, blob: &ContentSha256, src: &Path) -> Result<Option<CopyMethod>> {
+        if self.have_blob(blob) {
+            return Ok(None);
+        }
+
+        let blob_path = self.blob_file_path(blob);
+        let partial_path = self.partial_path(blob);
+
+        if !src.is_file() {
+            bail!("'{}' is not a regular file", src.display());
+        }
+        let source_file = OpenOptions::new().read(true).open(src)?;
+
+        let partial_parent = self.partial_parent(blob);
+        if partial_parent.exists() {
+            assert!(partial_parent.is_dir());
+        } else {
+            fs::create_dir(&partial_parent)?;
+        }
+
+        let partial_file = OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(&partial_path)?;
+
+        // TODO macos: fclonefileat and fcopyfile
+        // A leftover partial file would be in the way of a later attempt to store the same blob.
+        let (method, found) = match copy_file(&source_file, &partial_file) {
+            Ok(copied) => copied,
+            Err(err) => {
+                fs::remove_file(&partial_path)?;
+                return Err(err);
+            }
+        };
+        log::debug!("copied {} by {}", src.display(), method);
+        if &found != blob {
+            fs::remove_file(&partial_path)?;
+            bail!(DigestMismatch {
+                path: src.to_path_buf(),
+                expected: blob.clone(),
+                found,
+            });
+        }
+
+        partial_file.set_permissions(Permissions::from_mode(0o444))?;
+
+        let blob_parent = self.blob_parent(blob);
+        if blob_parent.exists() {
+            assert!(blob_parent.is_dir());
+        } else {
+            fs::create_dir(blob_parent)?;
+        }
+
+        fs::rename(&partial_path, &blob_path)?;
+        Ok(Some(method))
+    }
+
+    fn store_from_reader(&self, src: &mut dyn Read) -> Result<Shadow> {
+        let partial_path = self
+            .partial_dir()
+            .join(format!("reader-{:016x}", rand::random::<u64>()));
+        let mut partial_file = OpenOptions::new()
+            .create_new(true)
+            .write(true)
+            .open(&partial_path)?;
+        let result = (|| {
+            let shadow = copy_and_hash(src, &mut partial_file)?;
+            partial_file.set_permissions(Permissions::from_mode(0o444))?;
+            if !self.have_blob(shadow.content_hash()) {
+                let blob_parent = self.blob_parent(shadow.content_hash());
+                if !blob_parent.exists() {
+                    fs::create_dir(blob_parent)?;
+                }
+                fs::rename(&partial_path, self.blob_file_path(shadow.content_hash()))?;
+            }
+            Ok(shadow)
+        })();
+        if partial_path.exists() {
+            fs::remove_file(&partial_path)?;
+        }
+        result
+    }
+
+    fn have_blob(&self, blob: &ContentSha256) -> bool {
+        self.blob_file_path(blob).is_file()
+    }
+
+    fn open_blob(&self, blob: &ContentSha256) -> Result<Box<dyn BlobReader>> {
+        Ok(Box::new(File::open(self.blob_file_path(blob))?))
+    }
+
+    fn blob_path(&self, blob: &ContentSha256) -> Option<PathBuf> {
+        Some(self.blob_file_path(blob))
+    }
+
+    fn garbage(
+        &self,
+        reachable: &BTreeSet<ContentSha256>,
+        cutoff: SystemTime,
+    ) -> Result<Vec<PathBuf>> {
+        self.blob_garbage(cutoff, |name| is_unreachable(name, reachable))
+    }
+}
+
+pub struct MockSubstance {
+    token_blob_path: PathBuf,
+}
+
+impl MockSubstance {
+    pub fn new(token_blob_path: impl AsRef<Path>) -> Self {
+        Self {
+            token_blob_path: token_blob_path.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl Substance for MockSubstance {
+    fn store(&self, blob: &ContentSha256, src: &Path) -> Result<Option<CopyMethod>> {
+        check_sha256sum(blob, src, Sha256Implementation::default())?;
+        Ok(None)
+    }
+
+    fn store_from_reader(&self, src: &mut dyn Read) -> Result<Shadow> {
+        copy_and_hash(src, &mut io::sink())
+    }
+
+    fn have_blob(&self, _: &ContentSha256) -> bool {
+        self.token_blob_path.is_file()
+    }
+
+    fn open_blob(&self, _: &ContentSha256) -> Result<Box<dyn BlobReader>> {
+        Ok(Box::new(File::open(&self.token_blob_path)?))
+    }
+
+    fn blob_path(&self, _: &ContentSha256) -> Option<PathBuf> {
+        Some(self.token_blob_path.clone())
+    }
+
+    fn garbage(&self, _: &BTreeSet<ContentSha256>, _: SystemTime) -> Result<Vec<PathBuf>> {
+        Ok(vec![])
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum CopyMethod {
+    // The copy shares its extents with the source, on filesystems such as btrfs and XFS, and costs
+    // no space until either is modified.
+    Reflink,
+    Copy,
+    // Compressed into a new file.
+    Zstd,
+    // Encrypted into a new file.
+    Encrypt,
+    // Split into chunks, of which only new ones are copied.
+    Chunk,
+}
+
+impl fmt::Display for CopyMethod {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result {
+        fmt.write_str(match self {
+            Self::Reflink => "reflink",
+            Self::Copy => "copy",
+            Self::Zstd => "zstd",
+            Self::Encrypt => "encryption",
+            Self::Chunk => "chunking",
+        })
+    }
+}
+
+// _IOW(0x94, 9, int) from linux/fs.h, missing from libc.
+const FICLONE: libc::c_ulong = 0x40049409;
+
+// Copies `src` to the empty `dst` by a reflink where the filesystem supports it, and hashes the
+// copy. A reflink copies no data, so the copy is read once to hash it. Otherwise the content is
+// hashed as it is copied, so that it is still read only once.
+fn copy_file(src: &File, dst: &File) -> Result<(CopyMethod, ContentSha256)> {
+    if unsafe { libc::ioctl(dst.as_raw_fd(), FICLONE, src.as_raw_fd()) } != 0 {
+        let shadow = copy_and_hash(&mut &*src, &mut &*dst)?;
+        return Ok((CopyMethod::Copy, shadow.content_hash().clone()));
+    }
+    (&*dst).seek(SeekFrom::Start(0))?;
+    let shadow = copy_and_hash(&mut &*dst, &mut io::sink())?;
+    Ok((CopyMethod::Reflink, shadow.content_hash().clone()))
+}
+
+// Reads are of whole pages into a page-aligned buffer, large enough to amortize the syscalls.
+struct ReadBuffer {
+    ptr: NonNull<u8>,
+}
+
+impl ReadBuffer {
+    const SIZE: usize = 1 << 20;
+    const ALIGN: usize = 4096;
+
+    fn layout() -> Layout {
+        Layout::from_size_align(Self::SIZE, Self::ALIGN).unwrap()
+    }
+
+    fn new() -> Self {
+        let ptr = unsafe { alloc::alloc_zeroed(Self::layout()) };
+        Self {
+            ptr: NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(Self::layout())),
+        }
+    }
+}
+
+impl Deref for ReadBuffer {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), Self::SIZE) }
+    }
+}
+
+impl DerefMut for ReadBuffer {
+    fn deref_mut(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), Self::SIZE) }
+    }
+}
+
+impl Drop for ReadBuffer {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), Self::layout()) }
+    }
+}
+
+fn copy_and_hash(src: &mut dyn Read, dst: &mut dyn Write) -> Result<Shadow> {
+    let mut hasher = Sha256::new();
+    let mut buf = ReadBuffer::new();
+    let mut size = 0;
+    loop {
+        let n = match src.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        hasher.update(&buf[..n]);
+        dst.write_all(&buf[..n])?;
+        size += n as u64;
+    }
+    let hash = hasher.finalize();
+    Ok(Shadow::new(ContentSha256::from_slice(&hash), Some(size)))
+}
+
+// How to compute the digests of files. Reading in-process is the default. Coreutils is an
+// independent implementation, to cross-check with.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Sha256Implementation {
+    Read,
+    // Only for files that cannot change, such as blobs, as truncating a mapped file kills the
+    // process with SIGBUS.
+    Mmap,
+    Coreutils,
+}
+
+impl Default for Sha256Implementation {
+    fn default() -> Self {
+        Self::Read
+    }
+}
+
+impl FromStr for Sha256Implementation {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "read" => Self::Read,
+            "mmap" => Self::Mmap,
+            "coreutils" => Self::Coreutils,
+            _ => bail!("unknown sha256 implementation '{}'", s),
+        })
+    }
+}
+
+pub fn sha256sum_coreutils(path: &Path) -> Result<ContentSha256> {
+    let output = Command::new("sha256sum")
+        .arg("-bz")
+        .arg(path)
+        .stderr(Stdio::inherit())
+        .output()?;
+    parse_coreutils_output(output)
+}
+
+// For content that is not in a file, such as a decompressed blob.
+fn sha256sum_coreutils_reader(src: &mut dyn Read) -> Result<ContentSha256> {
+    let mut child = Command::new("sha256sum")
+        .arg("-bz")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()?;
+    io::copy(src, child.stdin.as_mut().unwrap())?;
+    parse_coreutils_output(child.wait_with_output()?)
+}
+
+fn parse_coreutils_output(output: Output) -> Result<ContentSha256> {
+    lazy_static! {
+        static ref RE: Regex =
+            Regex::new(r"(?s-u)(?P<digest>[a-z0-9]{64}|[?]{64}) \*(?P<path>.*)\x00").unwrap();
+    }
+    output.status.exit_ok()?;
+    let caps = RE
+        .captures(&output.stdout)
+        .ok_or(anyhow!("regex does not match"))?;
+    Ok(std::str::from_utf8(&caps["digest"])?.parse()?)
+}
+
+pub fn sha256sum_rust(path: &Path) -> Result<ContentSha256> {
+    let mut file = OpenOptions::new().read(true).open(path)?;
+    unsafe { libc::posix_fadvise(file.as_raw_fd(), 0, 0, libc::POSIX_FADV_SEQUENTIAL) };
+    let shadow = copy_and_hash(&mut file, &mut io::sink())?;
+    Ok(shadow.content_hash().clone())
+}
+
+pub fn sha256sum_mmap(path: &Path) -> Result<ContentSha256> {
+    let file = OpenOptions::new().read(true).open(path)?;
+    let len = file.metadata()?.len() as usize;
+    let mut hasher = Sha256::new();
+    // Empty mappings are not allowed.
+    if len > 0 {
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE,
+                file.as_raw_fd(),
+                0,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error().into());
+        }
+        unsafe {
+            libc::madvise(addr, len, libc::MADV_SEQUENTIAL);
+            hasher.update(slice::from_raw_parts(addr as *const u8, len));
+            libc::munmap(addr, len);
+        }
+    }
+    Ok(ContentSha256::from_slice(&hasher.finalize()))
+}
+
+pub fn sha256sum_with(path: &Path, implementation: Sha256Implementation) -> Result<ContentSha256> {
+    match implementation {
+        Sha256Implementation::Read => sha256sum_rust(path),
+        Sha256Implementation::Mmap => sha256sum_mmap(path),
+        Sha256Implementation::Coreutils => sha256sum_coreutils(path),
+    }
+}
+
+pub fn sha256sum(path: &Path) -> Result<ContentSha256> {
+    sha256sum_rust(path)
+}
+
+// Typically the source of a blob was modified after it was hashed.
+#[derive(Debug, Error)]
+#[error("'{}' has digest {found} instead of {expected}", .path.display())]
+pub struct DigestMismatch {
+    pub path: PathBuf,
+    pub expected: ContentSha256,
+    pub found: ContentSha256,
+}
+
+fn check_sha256sum(
+    expected: &ContentSha256,
+    path: &Path,
+    implementation: Sha256Implementation,
+) -> Result<()> {
+    let found = sha256sum_with(path, implementation)?;
+    if &found != expected {
+        bail!(DigestMismatch {
+            path: path.to_path_buf(),
+            expected: expected.clone(),
+            found,
+        });
+    }
+    Ok(())
+}
+
+// For blobs that are not kept as plain files, which can only be hashed as they are read back, and
+// so cannot be mapped.
+fn check_sha256sum_reader(
+    expected: &ContentSha256,
+    src: &mut dyn Read,
+    implementation: Sha256Implementation,
+) -> Result<()> {
+    let found = match implementation {
+        Sha256Implementation::Coreutils => sha256sum_coreutils_reader(src)?,
+        Sha256Implementation::Read | Sha256Implementation::Mmap => {
+            copy_and_hash(src, &mut io::sink())?.content_hash().clone()
+        }
+    };
+    if &found != expected {
+        bail!("blob {} reads back with digest {}", expected, found);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copy() {
+        let dir = std::env::temp_dir().join(format!("keep-copy-{:016x}", rand::random::<u64>()));
+        fs::create_dir(&dir).unwrap();
+        let content = (0..300_000u32).map(|i| i as u8).collect::<Vec<u8>>();
+        fs::write(dir.join("src"), &content).unwrap();
+        let dst = OpenOptions::new()
+            .create_new(true)
+            .read(true)
+            .write(true)
+            .open(dir.join("dst"))
+            .unwrap();
+        // Whether or not the filesystem supports reflinks, the copy is hashed in full.
+        let (_, digest) = copy_file(&File::open(dir.join("src")).unwrap(), &dst).unwrap();
+        assert_eq!(digest, ContentSha256::from_slice(&Sha256::digest(&content)));
+        assert_eq!(fs::read(dir.join("dst")).unwrap(), content);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}